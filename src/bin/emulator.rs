@@ -0,0 +1,57 @@
+//! Emulates the levitation firmware on a pseudo terminal, see
+//! `levitation::emulator`.
+//!
+//! usage: emulator [config file]
+//!
+//! Prints the port to connect to, e.g. `levitation --port /dev/pts/3`.
+//! The simulated rig uses the `[plant]` and `[pid]` sections of the
+//! config, `levitation.conf` by default.
+
+use std::time::Duration;
+
+use serialport::{SerialPort, TTYPort};
+
+use levitation::config::Config;
+use levitation::emulator::Emulator;
+
+// the firmware's loop period, also how long a read waits for the host
+const TICK: Duration = Duration::from_millis(1);
+
+fn usage() -> ! {
+    eprintln!("usage: emulator [config file]");
+    std::process::exit(1);
+}
+
+fn main() {
+    let mut path = "levitation.conf".to_string();
+    for arg in std::env::args().skip(1) {
+        if arg.starts_with('-') {
+            usage();
+        }
+        path = arg;
+    }
+
+    let config = Config::load_or_default(&path).expect("failed to load config");
+
+    let (mut port, host) = TTYPort::pair().expect("failed to open a pseudo terminal");
+    port.set_timeout(TICK).unwrap();
+    println!("emulating the rig on {}", host.name().unwrap());
+    // the host opens its end by name, and can close and reopen it
+    drop(host);
+
+    let mut emulator = Emulator::new(config.plant, config.pid);
+    let mut connected = false;
+    loop {
+        let res = emulator.poll(&mut port);
+        match (&res, connected) {
+            (Ok(()), false) => println!("host connected"),
+            (Err(_), true) => println!("host disconnected"),
+            _ => {}
+        }
+        connected = res.is_ok();
+        if res.is_err() {
+            // nothing to wait on without a host
+            std::thread::sleep(TICK);
+        }
+    }
+}
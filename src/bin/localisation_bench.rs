@@ -0,0 +1,109 @@
+//! Compares the `Localisation` methods on recorded frames.
+//!
+//! usage: localisation_bench <frames dir> <r> <g> <b> <tolerance>
+//!
+//! Jitter is the RMS of the second difference of consecutive positions,
+//! so a ball moving at constant speed doesn't count as jitter.
+
+use std::{path::PathBuf, time::Instant};
+
+use cv::prelude::*;
+use opencv as cv;
+
+use levitation::color::{Color, ColorRange};
+use levitation::config::Config;
+use levitation::{Localisation, Tracker};
+
+fn usage() -> ! {
+    eprintln!("usage: localisation_bench <frames dir> <r> <g> <b> <tolerance>");
+    std::process::exit(1);
+}
+
+fn jitter(positions: &[f32]) -> f32 {
+    if positions.len() < 3 {
+        return f32::NAN;
+    }
+
+    let sum: f32 = positions
+        .windows(3)
+        .map(|w| (w[2] - 2. * w[1] + w[0]).powi(2))
+        .sum();
+    (sum / (positions.len() - 2) as f32).sqrt()
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() != 5 {
+        usage();
+    }
+    let num = |i: usize| -> u8 { args[i].parse().unwrap_or_else(|_| usage()) };
+
+    let color = Color::new(num(1), num(2), num(3));
+    let tolerance = num(4);
+    let range = ColorRange::from_tolerance(color, Color::new(tolerance, tolerance, tolerance));
+
+    let dir = &args[0];
+    let entries = std::fs::read_dir(dir).unwrap_or_else(|e| {
+        eprintln!("failed to read {dir}: {e}");
+        std::process::exit(1);
+    });
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry.path()),
+            Err(e) => {
+                eprintln!("failed to read an entry of {dir}: {e}");
+                None
+            }
+        })
+        .collect();
+    paths.sort();
+
+    // skips anything that isn't an image
+    let frames: Vec<Mat> = paths
+        .iter()
+        .filter_map(|p| {
+            match cv::imgcodecs::imread(&p.to_string_lossy(), cv::imgcodecs::IMREAD_COLOR) {
+                Ok(frame) => Some(frame),
+                Err(e) => {
+                    eprintln!("failed to read {}: {e}", p.display());
+                    None
+                }
+            }
+        })
+        .filter(|frame| !frame.empty())
+        .collect();
+    println!("{} frames", frames.len());
+
+    let config = Config::load_or_default("levitation.conf").expect("failed to load config");
+
+    println!(
+        "{:<18} {:>10} {:>10} {:>10} {:>10}",
+        "method", "detected", "ms/frame", "jitter x", "jitter y"
+    );
+    for &localisation in Localisation::ALL {
+        let mut tracker = Tracker::with_params(range, config.blob);
+        tracker.set_selection(config.tracker.selection);
+        tracker.set_localisation(localisation);
+
+        let mut xs = Vec::new();
+        let mut ys = Vec::new();
+
+        let start = Instant::now();
+        for frame in &frames {
+            if let Some(ball) = tracker.track(frame) {
+                xs.push(ball.x);
+                ys.push(ball.y);
+            }
+        }
+        let elapsed = start.elapsed().as_secs_f32() * 1000.;
+
+        println!(
+            "{:<18} {:>10} {:>10.3} {:>10.4} {:>10.4}",
+            localisation.to_string(),
+            xs.len(),
+            elapsed / frames.len().max(1) as f32,
+            jitter(&xs),
+            jitter(&ys),
+        );
+    }
+}
@@ -0,0 +1,60 @@
+//! Runs the simulated rig in closed loop through the vision pipeline.
+//!
+//! usage: sil [config file] [--csv]
+//!
+//! Uses the `[pid]`, `[plant]` and `[sil]` sections of the config,
+//! `levitation.conf` by default. Exits with an error if the ball was lost,
+//! so it can run in CI. `--csv` prints every frame instead of a summary.
+
+use std::time::Duration;
+
+use levitation::config::Config;
+use levitation::sil::Harness;
+
+fn usage() -> ! {
+    eprintln!("usage: sil [config file] [--csv]");
+    std::process::exit(1);
+}
+
+fn main() {
+    let mut path = "levitation.conf".to_string();
+    let mut csv = false;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--csv" => csv = true,
+            _ if arg.starts_with('-') => usage(),
+            _ => path = arg,
+        }
+    }
+
+    let config = Config::load_or_default(&path).expect("failed to load config");
+    let report = Harness::new(config.sil, config.plant, config.pid).run();
+
+    if csv {
+        println!("time,gap,measured,command");
+        for s in &report.steps {
+            let measured = s.measured.map(|m| m.to_string()).unwrap_or_default();
+            println!(
+                "{:.4},{:.4},{},{:.4}",
+                s.time.as_secs_f64(),
+                s.gap,
+                measured,
+                s.command
+            );
+        }
+    } else {
+        // the first second is the ball settling from its start position
+        let settled = Duration::from_secs(1);
+        let mm = |error: Option<f32>| error.map_or("-".to_string(), |e| format!("{e:.3} mm"));
+        println!("frames       {}", report.steps.len());
+        println!("detections   {}", report.detections());
+        println!("vision error {}", mm(report.measurement_error()));
+        println!("rms error    {}", mm(report.rms_error(settled)));
+        println!("max error    {:.3} mm", report.max_error(settled));
+    }
+
+    if report.lost {
+        eprintln!("ball lost");
+        std::process::exit(1);
+    }
+}
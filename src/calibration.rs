@@ -0,0 +1,336 @@
+//! Converting image positions to millimetres.
+//!
+//! A pixel first goes through the tilt correcting homography (identity unless
+//! calibrated), then is measured from the corrected `origin` and multiplied
+//! by `scale`.
+
+use std::{fmt, str::FromStr};
+
+use crate::kalman::BallState;
+use crate::Ball;
+
+/// Projective transform between two planes, row-major with the last
+/// element normalised to 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Homography(pub [f64; 9]);
+
+impl Default for Homography {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+impl Homography {
+    pub const IDENTITY: Self = Self([1., 0., 0., 0., 1., 0., 0., 0., 1.]);
+
+    /// Transform mapping each `src` point onto the matching `dst` one.
+    /// `None` if three of the points are collinear.
+    pub fn from_points(src: [(f32, f32); 4], dst: [(f32, f32); 4]) -> Option<Self> {
+        // h * [x, y, 1] = w * [u, v, 1], with h[8] = 1, gives two equations per point
+        let mut a = [[0.; 9]; 8];
+        for (i, ((x, y), (u, v))) in src.into_iter().zip(dst).enumerate() {
+            let (x, y, u, v) = (x as f64, y as f64, u as f64, v as f64);
+            a[2 * i] = [x, y, 1., 0., 0., 0., -u * x, -u * y, u];
+            a[2 * i + 1] = [0., 0., 0., x, y, 1., -v * x, -v * y, v];
+        }
+
+        let h = solve(a)?;
+        Some(Self([h[0], h[1], h[2], h[3], h[4], h[5], h[6], h[7], 1.]))
+    }
+
+    /// Straightens a rectangle seen at an angle: maps the four clicked
+    /// corners (in order around the rectangle, starting at its top left)
+    /// onto an upright rectangle of about the same size in pixels.
+    pub fn rectify(corners: [(f32, f32); 4]) -> Option<Self> {
+        let length =
+            |a: (f32, f32), b: (f32, f32)| ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt();
+        let [tl, tr, br, bl] = corners;
+
+        let width = (length(tl, tr) + length(bl, br)) / 2.;
+        let height = (length(tl, bl) + length(tr, br)) / 2.;
+
+        Self::from_points(
+            corners,
+            [
+                tl,
+                (tl.0 + width, tl.1),
+                (tl.0 + width, tl.1 + height),
+                (tl.0, tl.1 + height),
+            ],
+        )
+    }
+
+    pub fn apply(&self, (x, y): (f32, f32)) -> (f32, f32) {
+        let h = &self.0;
+        let (x, y) = (x as f64, y as f64);
+
+        let w = h[6] * x + h[7] * y + h[8];
+        let u = (h[0] * x + h[1] * y + h[2]) / w;
+        let v = (h[3] * x + h[4] * y + h[5]) / w;
+        (u as f32, v as f32)
+    }
+}
+
+// gaussian elimination with partial pivoting on an augmented 8x9 matrix
+fn solve(mut a: [[f64; 9]; 8]) -> Option<[f64; 8]> {
+    const N: usize = 8;
+
+    for col in 0..N {
+        let pivot = (col..N).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+
+        for row in col + 1..N {
+            let factor = a[row][col] / a[col][col];
+            let pivot_row = a[col];
+            for (v, p) in a[row][col..].iter_mut().zip(&pivot_row[col..]) {
+                *v -= factor * p;
+            }
+        }
+    }
+
+    let mut x = [0.; N];
+    for row in (0..N).rev() {
+        let known: f64 = (row + 1..N).map(|col| a[row][col] * x[col]).sum();
+        x[row] = (a[row][N] - known) / a[row][row];
+    }
+    Some(x)
+}
+
+impl fmt::Display for Homography {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let values: Vec<String> = self.0.iter().map(|v| v.to_string()).collect();
+        f.write_str(&values.join(" "))
+    }
+}
+
+impl FromStr for Homography {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values: Vec<f64> = s
+            .split_whitespace()
+            .map(|v| v.parse().map_err(|_| ()))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self(values.try_into().map_err(|_| ())?))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    /// Millimetres per (tilt corrected) pixel
+    pub scale: f32,
+    /// Pixel that ends up at (0, 0) mm, before tilt correction so it
+    /// stays put when that changes
+    pub origin_x: f32,
+    pub origin_y: f32,
+    pub homography: Homography,
+}
+
+impl Default for Calibration {
+    // leaves everything in pixels
+    fn default() -> Self {
+        Self {
+            scale: 1.,
+            origin_x: 0.,
+            origin_y: 0.,
+            homography: Homography::IDENTITY,
+        }
+    }
+}
+
+crate::section!(
+    Calibration,
+    "calibration",
+    [scale, origin_x, origin_y, homography]
+);
+
+impl Calibration {
+    pub fn is_calibrated(&self) -> bool {
+        *self != Self::default()
+    }
+
+    /// Sets the scale from two points `distance` mm apart
+    pub fn calibrate_points(&mut self, a: (f32, f32), b: (f32, f32), distance: f32) -> bool {
+        let (a, b) = (self.homography.apply(a), self.homography.apply(b));
+        let pixels = ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt();
+
+        self.set_scale(distance / pixels)
+    }
+
+    /// Sets the scale from a detected round object of known `diameter` in mm
+    pub fn calibrate_diameter(&mut self, ball: &Ball, diameter: f32) -> bool {
+        let (x, y) = (ball.x, ball.y);
+        self.calibrate_points((x - ball.radius, y), (x + ball.radius, y), diameter)
+    }
+
+    /// Corrects for camera tilt from the corners of a rectangle, see
+    /// `Homography::rectify`. Resets the scale, which was measured in the
+    /// old corrected pixels and has to be calibrated again.
+    pub fn calibrate_tilt(&mut self, corners: [(f32, f32); 4]) -> bool {
+        match Homography::rectify(corners) {
+            Some(h) => {
+                self.homography = h;
+                self.scale = 1.;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Makes `origin` (in raw pixels) the (0, 0) mm point
+    pub fn set_origin(&mut self, origin: (f32, f32)) {
+        (self.origin_x, self.origin_y) = origin;
+    }
+
+    fn set_scale(&mut self, scale: f32) -> bool {
+        let valid = scale.is_finite() && scale > 0.;
+        if valid {
+            self.scale = scale;
+        }
+        valid
+    }
+
+    pub fn to_mm(&self, point: (f32, f32)) -> (f32, f32) {
+        let (x, y) = self.homography.apply(point);
+        let (origin_x, origin_y) = self.homography.apply((self.origin_x, self.origin_y));
+        ((x - origin_x) * self.scale, (y - origin_y) * self.scale)
+    }
+
+    // maps a displacement at `point`, using the local linearisation of `to_mm`
+    fn vector_to_mm(&self, point: (f32, f32), (dx, dy): (f32, f32)) -> (f32, f32) {
+        const STEP: f32 = 1.;
+
+        let len = (dx * dx + dy * dy).sqrt();
+        if len == 0. {
+            return (0., 0.);
+        }
+
+        let from = self.to_mm(point);
+        let to = self.to_mm((point.0 + dx / len * STEP, point.1 + dy / len * STEP));
+        ((to.0 - from.0) * len / STEP, (to.1 - from.1) * len / STEP)
+    }
+
+    /// Position and radius in mm, area in mm²
+    pub fn ball_to_mm(&self, ball: &Ball) -> Ball {
+        let (x, y) = self.to_mm((ball.x, ball.y));
+        let (radius, _) = self.vector_to_mm((ball.x, ball.y), (ball.radius, 0.));
+
+        Ball {
+            x,
+            y,
+            radius: radius.abs(),
+            area: ball.area * self.scale * self.scale,
+            ..*ball
+        }
+    }
+
+    /// Position, velocity and acceleration in mm and seconds.
+    /// The covariance is only scaled, ignoring any tilt correction.
+    pub fn state_to_mm(&self, state: &BallState) -> BallState {
+        let position = (state.x, state.y);
+        let (x, y) = self.to_mm(position);
+        let (vx, vy) = self.vector_to_mm(position, (state.vx, state.vy));
+        let (ax, ay) = self.vector_to_mm(position, (state.ax, state.ay));
+
+        let mut covariance = state.covariance;
+        for v in covariance.iter_mut().flatten().flatten() {
+            *v *= self.scale * self.scale;
+        }
+
+        BallState {
+            x,
+            y,
+            vx,
+            vy,
+            ax,
+            ay,
+            covariance,
+            ..*state
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: (f32, f32), b: (f32, f32)) {
+        assert!(
+            (a.0 - b.0).abs() < 1e-3 && (a.1 - b.1).abs() < 1e-3,
+            "{a:?} != {b:?}"
+        );
+    }
+
+    #[test]
+    fn calibration_scale() {
+        let mut cal = Calibration::default();
+        assert!(!cal.is_calibrated());
+        assert_close(cal.to_mm((12., 34.)), (12., 34.));
+
+        assert!(cal.calibrate_points((10., 10.), (10., 110.), 50.));
+        assert_eq!(cal.scale, 0.5);
+        assert!(!cal.calibrate_points((10., 10.), (10., 10.), 50.));
+        assert_eq!(cal.scale, 0.5);
+
+        cal.set_origin((100., 100.));
+        assert_close(cal.to_mm((120., 80.)), (10., -10.));
+
+        let ball = Ball {
+            x: 100.,
+            y: 100.,
+            radius: 20.,
+            ..Default::default()
+        };
+        assert!(cal.calibrate_diameter(&ball, 10.));
+        assert_eq!(cal.scale, 0.25);
+    }
+
+    #[test]
+    fn homography() {
+        let src = [(10., 10.), (90., 20.), (80., 95.), (15., 85.)];
+        let dst = [(0., 0.), (100., 0.), (100., 100.), (0., 100.)];
+        let h = Homography::from_points(src, dst).unwrap();
+        for (s, d) in src.into_iter().zip(dst) {
+            assert_close(h.apply(s), d);
+        }
+
+        let collinear = [(0., 0.), (1., 1.), (2., 2.), (3., 3.)];
+        assert_eq!(Homography::from_points(collinear, dst), None);
+
+        let parsed: Homography = h.to_string().parse().unwrap();
+        assert_eq!(parsed, h);
+        assert!("1 2 3".parse::<Homography>().is_err());
+    }
+
+    #[test]
+    fn calibration_tilt() {
+        // a 100x50 rectangle seen with its top edge further away
+        let corners = [(20., 0.), (100., 0.), (120., 50.), (0., 50.)];
+
+        let mut cal = Calibration::default();
+        cal.set_origin((60., 25.));
+        assert!(cal.calibrate_points((0., 0.), (0., 50.), 25.));
+        assert!(cal.calibrate_tilt(corners));
+        // the origin stays where it was clicked, the scale has to be redone
+        assert_close(cal.to_mm((60., 25.)), (0., 0.));
+        assert_eq!(cal.scale, 1.);
+
+        let [tl, tr, br, bl] = corners.map(|c| cal.to_mm(c));
+        assert_close((tr.0 - tl.0, tr.1 - tl.1), (br.0 - bl.0, br.1 - bl.1));
+        assert_close((bl.0 - tl.0, bl.1 - tl.1), (br.0 - tr.0, br.1 - tr.1));
+        assert!((tl.0 - bl.0).abs() < 1e-3);
+
+        let state = BallState {
+            x: 60.,
+            y: 25.,
+            vy: 10.,
+            ..Default::default()
+        };
+        let state = cal.state_to_mm(&state);
+        assert!(state.vx.abs() < 1e-3 && state.vy > 0., "{state:?}");
+    }
+}
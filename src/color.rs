@@ -52,6 +52,328 @@ impl Color {
     }
 }
 
+// Cylindrical color spaces
+//
+// Both use OpenCV's 8-bit conventions so they can be compared directly
+// against frames converted with `COLOR_BGR2HSV` / `COLOR_BGR2HLS`:
+// hue is in degrees / 2 (0..180), everything else is scaled to 0..=255.
+
+pub const HUE_MAX: u8 = 180;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Hsv {
+    pub h: u8,
+    pub s: u8,
+    pub v: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Hsl {
+    pub h: u8,
+    pub s: u8,
+    pub l: u8,
+}
+
+impl Hsv {
+    pub fn new(h: u8, s: u8, v: u8) -> Self {
+        Self { h, s, v }
+    }
+
+    pub fn to_rgb(self) -> Color {
+        let h = self.h as f32 * 2.;
+        let s = self.s as f32 / 255.;
+        let v = self.v as f32 / 255.;
+
+        let c = v * s;
+        hue_to_rgb(h, c, v - c)
+    }
+}
+
+impl Hsl {
+    pub fn new(h: u8, s: u8, l: u8) -> Self {
+        Self { h, s, l }
+    }
+
+    pub fn to_rgb(self) -> Color {
+        let h = self.h as f32 * 2.;
+        let s = self.s as f32 / 255.;
+        let l = self.l as f32 / 255.;
+
+        let c = (1. - (2. * l - 1.).abs()) * s;
+        hue_to_rgb(h, c, l - c / 2.)
+    }
+}
+
+impl From<Color> for Hsv {
+    fn from(color: Color) -> Self {
+        color.to_hsv()
+    }
+}
+
+impl From<Color> for Hsl {
+    fn from(color: Color) -> Self {
+        color.to_hsl()
+    }
+}
+
+impl From<Hsv> for Color {
+    fn from(hsv: Hsv) -> Self {
+        hsv.to_rgb()
+    }
+}
+
+impl From<Hsl> for Color {
+    fn from(hsl: Hsl) -> Self {
+        hsl.to_rgb()
+    }
+}
+
+impl Color {
+    pub fn to_hsv(self) -> Hsv {
+        let (max, min) = self.max_min();
+        let delta = max - min;
+
+        let s = if max > 0. { delta / max } else { 0. };
+
+        Hsv {
+            h: self.hue(max, delta),
+            s: (s * 255.).round() as u8,
+            v: (max * 255.).round() as u8,
+        }
+    }
+
+    pub fn to_hsl(self) -> Hsl {
+        let (max, min) = self.max_min();
+        let delta = max - min;
+        let l = (max + min) / 2.;
+
+        let s = if delta == 0. {
+            0.
+        } else if l < 0.5 {
+            delta / (max + min)
+        } else {
+            delta / (2. - max - min)
+        };
+
+        Hsl {
+            h: self.hue(max, delta),
+            s: (s * 255.).round() as u8,
+            l: (l * 255.).round() as u8,
+        }
+    }
+
+    fn normalized(&self) -> [f32; 3] {
+        self.channels.map(|c| c as f32 / 255.)
+    }
+
+    fn max_min(&self) -> (f32, f32) {
+        let [r, g, b] = self.normalized();
+        (r.max(g).max(b), r.min(g).min(b))
+    }
+
+    // hue in OpenCV units (degrees / 2)
+    fn hue(&self, max: f32, delta: f32) -> u8 {
+        if delta == 0. {
+            return 0;
+        }
+        let [r, g, b] = self.normalized();
+
+        let h = if max == r {
+            60. * (g - b) / delta
+        } else if max == g {
+            120. + 60. * (b - r) / delta
+        } else {
+            240. + 60. * (r - g) / delta
+        };
+        let h = if h < 0. { h + 360. } else { h };
+
+        ((h / 2.).round() as u8) % HUE_MAX
+    }
+}
+
+// `h` in degrees, `c` the chroma and `m` the amount added to every channel
+fn hue_to_rgb(h: f32, c: f32, m: f32) -> Color {
+    let h = h / 60.;
+    let x = c * (1. - (h % 2. - 1.).abs());
+
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.),
+        1 => (x, c, 0.),
+        2 => (0., c, x),
+        3 => (0., x, c),
+        4 => (x, 0., c),
+        _ => (c, 0., x),
+    };
+
+    let to_u8 = |v: f32| ((v + m) * 255.).round() as u8;
+    Color::new(to_u8(r), to_u8(g), to_u8(b))
+}
+
+// Ranges
+
+/// Types whose three channels can be thresholded independently.
+pub trait Channels: Copy {
+    /// Whether the first channel is a hue that wraps around at `HUE_MAX`.
+    const HUE: bool = false;
+
+    fn to_channels(self) -> [u8; 3];
+    fn from_channels(channels: [u8; 3]) -> Self;
+}
+
+impl Channels for Color {
+    fn to_channels(self) -> [u8; 3] {
+        self.channels
+    }
+    fn from_channels(channels: [u8; 3]) -> Self {
+        Self { channels }
+    }
+}
+
+impl Channels for Hsv {
+    const HUE: bool = true;
+
+    fn to_channels(self) -> [u8; 3] {
+        [self.h, self.s, self.v]
+    }
+    fn from_channels([h, s, v]: [u8; 3]) -> Self {
+        Self { h, s, v }
+    }
+}
+
+impl Channels for Hsl {
+    const HUE: bool = true;
+
+    fn to_channels(self) -> [u8; 3] {
+        [self.h, self.s, self.l]
+    }
+    fn from_channels([h, s, l]: [u8; 3]) -> Self {
+        Self { h, s, l }
+    }
+}
+
+/// Inclusive per-channel bounds.
+///
+/// For hue based colors `lower.h > upper.h` is allowed and means the range
+/// wraps around through red.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ColorRange<C = Color> {
+    pub lower: C,
+    pub upper: C,
+}
+
+impl<C: Channels> ColorRange<C> {
+    pub fn new(lower: C, upper: C) -> Self {
+        Self { lower, upper }
+    }
+
+    /// `center ± tolerance` on every channel
+    pub fn from_tolerance(center: C, tolerance: C) -> Self {
+        let center = center.to_channels();
+        let tolerance = tolerance.to_channels();
+
+        let mut lower = [0; 3];
+        let mut upper = [0; 3];
+        for i in 0..3 {
+            lower[i] = center[i].saturating_sub(tolerance[i]);
+            upper[i] = center[i].saturating_add(tolerance[i]);
+        }
+
+        if C::HUE {
+            let (h, tol) = (center[0] as i32, tolerance[0] as i32);
+            let hue_max = HUE_MAX as i32;
+
+            if 2 * tol + 1 >= hue_max {
+                lower[0] = 0;
+                upper[0] = HUE_MAX - 1;
+            } else {
+                lower[0] = (h - tol).rem_euclid(hue_max) as u8;
+                upper[0] = (h + tol).rem_euclid(hue_max) as u8;
+            }
+        }
+
+        Self::new(C::from_channels(lower), C::from_channels(upper))
+    }
+
+    /// Smallest range containing every sample
+    pub fn from_samples(samples: &[C]) -> Option<Self> {
+        Self::from_percentiles(samples, 0., 100.)
+    }
+
+    /// Range between the `low` and `high` percentiles (0-100) of each channel,
+    /// which ignores a few outlier pixels instead of stretching to fit them.
+    pub fn from_percentiles(samples: &[C], low: f32, high: f32) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+
+        let percentile = |sorted: &[u8], p: f32| {
+            let p = p.clamp(0., 100.) / 100.;
+            sorted[(p * (sorted.len() - 1) as f32).round() as usize]
+        };
+
+        let mut lower = [0; 3];
+        let mut upper = [0; 3];
+        for i in 0..3 {
+            let mut values: Vec<u8> = samples.iter().map(|c| c.to_channels()[i]).collect();
+
+            if C::HUE && i == 0 {
+                // measure hues from the start of the arc covering them,
+                // so a red cluster around 0 doesn't span the whole range
+                let start = hue_arc_start(&values);
+                let shift = |h: u8| (h as i32 - start as i32).rem_euclid(HUE_MAX as i32) as u8;
+                let unshift = |h: u8| ((h as u16 + start as u16) % HUE_MAX as u16) as u8;
+
+                let mut shifted: Vec<u8> = values.iter().map(|&h| shift(h)).collect();
+                shifted.sort_unstable();
+                lower[i] = unshift(percentile(&shifted, low));
+                upper[i] = unshift(percentile(&shifted, high));
+            } else {
+                values.sort_unstable();
+                lower[i] = percentile(&values, low);
+                upper[i] = percentile(&values, high);
+            }
+        }
+
+        Some(Self::new(C::from_channels(lower), C::from_channels(upper)))
+    }
+
+    pub fn contains(&self, color: C) -> bool {
+        let color = color.to_channels();
+        let lower = self.lower.to_channels();
+        let upper = self.upper.to_channels();
+
+        (0..3).all(|i| {
+            if C::HUE && i == 0 && lower[0] > upper[0] {
+                color[0] >= lower[0] || color[0] <= upper[0]
+            } else {
+                (lower[i]..=upper[i]).contains(&color[i])
+            }
+        })
+    }
+}
+
+// first hue after the largest gap between consecutive hues. A hue past
+// `HUE_MAX`, e.g. from a config file, goes around again like everywhere else.
+fn hue_arc_start(hues: &[u8]) -> u8 {
+    let mut sorted: Vec<u8> = hues.iter().map(|h| h % HUE_MAX).collect();
+    sorted.sort_unstable();
+    sorted.dedup();
+
+    let first = sorted[0];
+    let last = sorted[sorted.len() - 1];
+
+    let mut start = first;
+    let mut largest_gap = HUE_MAX - last + first;
+    for pair in sorted.windows(2) {
+        if pair[1] - pair[0] > largest_gap {
+            largest_gap = pair[1] - pair[0];
+            start = pair[1];
+        }
+    }
+
+    start
+}
+
 // Operations for color
 
 use std::ops;
@@ -296,4 +618,78 @@ mod tests {
         assert_eq!(Color::new(30, 30, 30) - 20, Color::new(10, 10, 10));
         assert_eq!(Color::new(30, 30, 30) - 40, Color::new(0, 0, 0));
     }
+
+    #[test]
+    fn color_hsv() {
+        assert_eq!(Color::red().to_hsv(), Hsv::new(0, 255, 255));
+        assert_eq!(Color::green().to_hsv(), Hsv::new(60, 255, 255));
+        assert_eq!(Color::blue().to_hsv(), Hsv::new(120, 255, 255));
+        assert_eq!(Color::new(128, 128, 128).to_hsv(), Hsv::new(0, 0, 128));
+        // just below red, wraps to the top of the hue range
+        assert_eq!(Color::new(255, 0, 8).to_hsv().h, 179);
+
+        let orange = Color::new(255, 128, 0);
+        assert_eq!(Color::from(orange.to_hsv()), orange);
+    }
+
+    #[test]
+    fn color_hsl() {
+        assert_eq!(Color::red().to_hsl(), Hsl::new(0, 255, 128));
+        assert_eq!(Color::white().to_hsl(), Hsl::new(0, 0, 255));
+        assert_eq!(Color::black().to_hsl(), Hsl::new(0, 0, 0));
+
+        let teal = Color::new(0, 128, 128);
+        assert_eq!(Color::from(teal.to_hsl()), teal);
+    }
+
+    #[test]
+    fn color_range() {
+        let range = ColorRange::from_tolerance(Color::new(10, 100, 250), Color::new(20, 5, 10));
+        assert_eq!(range.lower, Color::new(0, 95, 240));
+        assert_eq!(range.upper, Color::new(30, 105, 255));
+        assert!(range.contains(Color::new(25, 100, 255)));
+        assert!(!range.contains(Color::new(25, 110, 255)));
+
+        let samples = [
+            Color::new(10, 20, 30),
+            Color::new(12, 18, 31),
+            Color::new(11, 25, 29),
+        ];
+        let range = ColorRange::from_samples(&samples).unwrap();
+        assert_eq!(range.lower, Color::new(10, 18, 29));
+        assert_eq!(range.upper, Color::new(12, 25, 31));
+
+        let mut samples: Vec<Color> = (0..=100).map(|i| Color::new(i, i, i)).collect();
+        samples.push(Color::white());
+        let range = ColorRange::from_percentiles(&samples, 5., 95.).unwrap();
+        assert_eq!(range.lower, Color::new(5, 5, 5));
+        assert_eq!(range.upper, Color::new(96, 96, 96));
+
+        assert_eq!(ColorRange::<Color>::from_samples(&[]), None);
+    }
+
+    #[test]
+    fn hue_range_wraps() {
+        let range = ColorRange::from_tolerance(Hsv::new(2, 200, 200), Hsv::new(5, 50, 50));
+        assert_eq!(range.lower, Hsv::new(177, 150, 150));
+        assert_eq!(range.upper, Hsv::new(7, 250, 250));
+        assert!(range.contains(Hsv::new(178, 200, 200)));
+        assert!(range.contains(Hsv::new(6, 200, 200)));
+        assert!(!range.contains(Hsv::new(90, 200, 200)));
+
+        let range = ColorRange::from_tolerance(Hsv::new(90, 0, 0), Hsv::new(90, 0, 0));
+        assert_eq!((range.lower.h, range.upper.h), (0, HUE_MAX - 1));
+
+        let samples = [Hsv::new(175, 0, 0), Hsv::new(3, 0, 0), Hsv::new(179, 0, 0)];
+        let range = ColorRange::from_samples(&samples).unwrap();
+        assert_eq!((range.lower.h, range.upper.h), (175, 3));
+
+        // out of range hues wrap instead of overflowing
+        let samples = [Hsv::new(182, 0, 0), Hsv::new(178, 0, 0)];
+        let range = ColorRange::from_samples(&samples).unwrap();
+        assert_eq!((range.lower.h, range.upper.h), (178, 2));
+        let range = ColorRange::from_tolerance(Hsv::new(200, 0, 0), Hsv::new(5, 0, 0));
+        assert_eq!((range.lower.h, range.upper.h), (15, 25));
+        assert!(range.contains(Hsv::new(20, 0, 0)));
+    }
 }
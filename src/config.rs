@@ -0,0 +1,266 @@
+//! Plain text settings file.
+//!
+//! ```text
+//! # comments start with '#'
+//! [blob]
+//! filter_by_area = true
+//! min_area = 40
+//! ```
+//!
+//! Every group of settings implements `Section`, missing keys keep their
+//! default value and unknown ones are an error, so typos don't go unnoticed.
+
+use std::{fmt, fs, io, path::Path, str::FromStr};
+
+use crate::calibration::Calibration;
+use crate::control::PidConfig;
+use crate::kalman::KalmanConfig;
+use crate::lens::LensConfig;
+use crate::plant::PlantConfig;
+use crate::safety::SafetyConfig;
+use crate::serial::SerialConfig;
+use crate::sil::SilConfig;
+use crate::sink::SinkConfig;
+use crate::source::SourceConfig;
+use crate::tracker::{BlobParams, TrackerConfig};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse { line: usize, msg: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "could not read config: {e}"),
+            Self::Parse { line, msg } => write!(f, "config line {line}: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// A group of settings stored under `[NAME]`
+pub trait Section {
+    const NAME: &'static str;
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String>;
+    fn entries(&self) -> Vec<(&'static str, String)>;
+}
+
+pub fn parse_value<T: FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value `{value}`"))
+}
+
+/// Implements `Section` for a struct whose fields all implement
+/// `FromStr` + `Display`, using the field names as keys
+#[macro_export]
+macro_rules! section {
+    ($ty:ty, $name:literal, [$($field:ident),* $(,)?]) => {
+        impl $crate::config::Section for $ty {
+            const NAME: &'static str = $name;
+
+            fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+                match key {
+                    $(stringify!($field) => self.$field = $crate::config::parse_value(value)?,)*
+                    _ => return Err(format!("unknown key `{key}`")),
+                }
+                Ok(())
+            }
+
+            fn entries(&self) -> Vec<(&'static str, String)> {
+                vec![$((stringify!($field), self.$field.to_string()),)*]
+            }
+        }
+    };
+}
+
+/// Implements `Display` + `FromStr` for a fieldless enum so it can be
+/// used in a `Section`, and lists every variant in `ALL`
+#[macro_export]
+macro_rules! keywords {
+    ($ty:ty, [$($variant:ident => $name:literal),* $(,)?]) => {
+        impl $ty {
+            pub const ALL: &'static [Self] = &[$(Self::$variant),*];
+        }
+
+        impl std::fmt::Display for $ty {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(match self {
+                    $(Self::$variant => $name,)*
+                })
+            }
+        }
+
+        impl std::str::FromStr for $ty {
+            type Err = ();
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                match s {
+                    $($name => Ok(Self::$variant),)*
+                    _ => Err(()),
+                }
+            }
+        }
+    };
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    pub source: SourceConfig,
+    pub tracker: TrackerConfig,
+    pub blob: BlobParams,
+    pub kalman: KalmanConfig,
+    pub serial: SerialConfig,
+    pub safety: SafetyConfig,
+    pub sink: SinkConfig,
+    pub pid: PidConfig,
+    pub plant: PlantConfig,
+    pub sil: SilConfig,
+    pub calibration: Calibration,
+    pub lens: LensConfig,
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    /// Like `load`, but a missing file just means the defaults
+    pub fn load_or_default(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        match Self::load(path) {
+            Err(ConfigError::Io(e)) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            res => res,
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    fn set(&mut self, section: &str, key: &str, value: &str) -> Result<(), String> {
+        match section {
+            SourceConfig::NAME => self.source.set(key, value),
+            TrackerConfig::NAME => self.tracker.set(key, value),
+            BlobParams::NAME => self.blob.set(key, value),
+            KalmanConfig::NAME => self.kalman.set(key, value),
+            SerialConfig::NAME => self.serial.set(key, value),
+            SafetyConfig::NAME => self.safety.set(key, value),
+            SinkConfig::NAME => self.sink.set(key, value),
+            PidConfig::NAME => self.pid.set(key, value),
+            PlantConfig::NAME => self.plant.set(key, value),
+            SilConfig::NAME => self.sil.set(key, value),
+            Calibration::NAME => self.calibration.set(key, value),
+            LensConfig::NAME => self.lens.set(key, value),
+            _ => Err(format!("unknown section `[{section}]`")),
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Self::default();
+        let mut section = None;
+
+        for (i, line) in s.lines().enumerate() {
+            let error = |msg| ConfigError::Parse { line: i + 1, msg };
+
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = Some(name.trim());
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error("expected `key = value`".to_string()))?;
+            let section = section.ok_or_else(|| error("key outside of a section".to_string()))?;
+
+            config
+                .set(section, key.trim(), value.trim())
+                .map_err(error)?;
+        }
+
+        Ok(config)
+    }
+}
+
+fn write_section<S: Section>(f: &mut fmt::Formatter<'_>, section: &S) -> fmt::Result {
+    writeln!(f, "[{}]", S::NAME)?;
+    for (key, value) in section.entries() {
+        writeln!(f, "{key} = {value}")?;
+    }
+    writeln!(f)
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_section(f, &self.source)?;
+        write_section(f, &self.tracker)?;
+        write_section(f, &self.blob)?;
+        write_section(f, &self.kalman)?;
+        write_section(f, &self.serial)?;
+        write_section(f, &self.safety)?;
+        write_section(f, &self.sink)?;
+        write_section(f, &self.pid)?;
+        write_section(f, &self.plant)?;
+        write_section(f, &self.sil)?;
+        write_section(f, &self.calibration)?;
+        write_section(f, &self.lens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::Selection;
+
+    #[test]
+    fn config_round_trip() {
+        let mut config = Config::default();
+        config.blob.filter_by_area = true;
+        config.blob.min_area = 42.5;
+        config.tracker.selection = Selection::ClosestToPrevious;
+
+        let parsed: Config = config.to_string().parse().unwrap();
+        assert_eq!(parsed, config);
+    }
+
+    #[test]
+    fn config_parse() {
+        let config: Config = "
+            # only the area filter
+            [blob]
+            filter_by_area = true # inline comment
+            min_area=12
+        "
+        .parse()
+        .unwrap();
+        assert!(config.blob.filter_by_area);
+        assert_eq!(config.blob.min_area, 12.);
+        assert_eq!(config.blob.max_area, BlobParams::default().max_area);
+
+        assert!(matches!(
+            "[blob]\nmin_area = twelve".parse::<Config>(),
+            Err(ConfigError::Parse { line: 2, .. })
+        ));
+        assert!("[blob]\nmin_arae = 1".parse::<Config>().is_err());
+        assert!("[nope]\nmin_area = 1".parse::<Config>().is_err());
+        assert!("min_area = 1".parse::<Config>().is_err());
+    }
+}
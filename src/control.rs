@@ -0,0 +1,254 @@
+//! Closed loop control of the coil, from the ball's measured gap to the
+//! command sent over serial.
+
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PidConfig {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+    /// Time constant of the low-pass filter on the derivative, in seconds.
+    /// 0 disables it.
+    pub d_filter: f32,
+    /// Gap to hold the ball at, in mm below the magnet
+    pub setpoint: f32,
+    /// Output with no error, e.g. the duty cycle that about holds the ball up
+    pub bias: f32,
+    pub output_min: f32,
+    pub output_max: f32,
+}
+
+impl Default for PidConfig {
+    fn default() -> Self {
+        // tuned on the default `PlantConfig`, measured at 100 to 200 Hz
+        Self {
+            kp: 0.015,
+            ki: 0.1,
+            kd: 0.0007,
+            d_filter: 0.005,
+            setpoint: 15.,
+            bias: 0.5,
+            output_min: 0.,
+            output_max: 1.,
+        }
+    }
+}
+
+crate::section!(
+    PidConfig,
+    "pid",
+    [kp, ki, kd, d_filter, setpoint, bias, output_min, output_max]
+);
+
+/// PID controller where the output grows the further the ball is below
+/// the setpoint, so all gains are positive.
+///
+/// The derivative acts on the measurement rather than the error, so moving
+/// the setpoint doesn't kick the output, and is low-pass filtered since
+/// differentiating camera measurements amplifies their noise. The integral
+/// stops growing while the output is saturated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pid {
+    config: PidConfig,
+    /// already multiplied by `ki`, so changing it doesn't make the output jump
+    integral: f32,
+    /// filtered rate of change of the measurement
+    derivative: f32,
+    last: Option<(f32, Duration)>,
+    output: f32,
+}
+
+impl Pid {
+    pub fn new(config: PidConfig) -> Self {
+        let mut pid = Self {
+            config,
+            integral: 0.,
+            derivative: 0.,
+            last: None,
+            output: 0.,
+        };
+        pid.reset();
+        pid
+    }
+
+    pub fn config(&self) -> PidConfig {
+        self.config
+    }
+
+    /// Gains and setpoint can change while running without the output
+    /// jumping: the step in the proportional term is moved into the integral,
+    /// which then winds it in gradually.
+    pub fn set_config(&mut self, config: PidConfig) {
+        if let Some((measurement, _)) = self.last {
+            let p = |c: &PidConfig| c.kp * (measurement - c.setpoint);
+            self.integral += p(&self.config) - p(&config);
+        }
+        self.config = config;
+    }
+
+    pub fn set_setpoint(&mut self, setpoint: f32) {
+        self.set_config(PidConfig {
+            setpoint,
+            ..self.config
+        });
+    }
+
+    /// Forgets the history, e.g. after the ball was lost
+    pub fn reset(&mut self) {
+        self.integral = 0.;
+        self.derivative = 0.;
+        self.last = None;
+        self.output = self
+            .config
+            .bias
+            .clamp(self.config.output_min, self.config.output_max);
+    }
+
+    /// Last output, or the bias before the first update
+    pub fn output(&self) -> f32 {
+        self.output
+    }
+
+    /// `measurement` is the gap in mm, measured at `timestamp`.
+    /// Returns the new output, clamped to the configured limits.
+    pub fn update(&mut self, measurement: f32, timestamp: Duration) -> f32 {
+        let c = self.config;
+        let error = measurement - c.setpoint;
+
+        // the first measurement has nothing to integrate or differentiate against
+        let mut dt = 0.;
+        if let Some((last, last_time)) = self.last {
+            dt = timestamp.saturating_sub(last_time).as_secs_f32();
+            if dt > 0. {
+                let rate = (measurement - last) / dt;
+                self.derivative += dt / (c.d_filter + dt) * (rate - self.derivative);
+            }
+        }
+
+        let integral = self.integral + c.ki * error * dt;
+        let unclamped = c.bias + c.kp * error + integral + c.kd * self.derivative;
+        let output = unclamped.clamp(c.output_min, c.output_max);
+
+        // anti-windup: only integrate if it doesn't push further into saturation
+        if output == unclamped || (unclamped > output) != (error > 0.) {
+            self.integral = integral;
+        }
+
+        self.last = Some((measurement, timestamp));
+        self.output = output;
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(t: f64) -> Duration {
+        Duration::from_secs_f64(t)
+    }
+
+    fn config() -> PidConfig {
+        PidConfig {
+            kp: 0.1,
+            ki: 0.,
+            kd: 0.,
+            d_filter: 0.,
+            setpoint: 10.,
+            bias: 0.5,
+            output_min: 0.,
+            output_max: 1.,
+        }
+    }
+
+    #[test]
+    fn pid_terms() {
+        let mut pid = Pid::new(config());
+        assert_eq!(pid.output(), 0.5);
+
+        // ball 2 mm too low, pull harder
+        assert!((pid.update(12., secs(0.)) - 0.7).abs() < 1e-6);
+        assert!((pid.update(8., secs(0.01)) - 0.3).abs() < 1e-6);
+
+        // clamped
+        assert_eq!(pid.update(100., secs(0.02)), 1.);
+        assert_eq!(pid.update(-100., secs(0.03)), 0.);
+
+        let mut pid = Pid::new(PidConfig {
+            kp: 0.,
+            ki: 1.,
+            ..config()
+        });
+        pid.update(11., secs(0.));
+        let out = pid.update(11., secs(0.1));
+        assert!((out - 0.6).abs() < 1e-6, "{out}");
+
+        // derivative on the measurement, filtered
+        let mut pid = Pid::new(PidConfig {
+            kp: 0.,
+            kd: 0.002,
+            ..config()
+        });
+        pid.update(10., secs(0.));
+        let out = pid.update(11., secs(0.01));
+        assert!((out - 0.7).abs() < 1e-6, "{out}");
+
+        pid.set_config(PidConfig {
+            d_filter: 0.09,
+            ..pid.config()
+        });
+        pid.reset();
+        pid.update(10., secs(0.));
+        let out = pid.update(11., secs(0.01));
+        assert!((out - 0.52).abs() < 1e-6, "{out}");
+    }
+
+    #[test]
+    fn pid_bumpless() {
+        let mut pid = Pid::new(PidConfig {
+            ki: 0.5,
+            kd: 0.01,
+            d_filter: 0.02,
+            ..config()
+        });
+        for i in 0..10 {
+            pid.update(12., secs(i as f64 * 0.01));
+        }
+        let before = pid.output();
+
+        // neither a new setpoint nor new gains make the output jump
+        pid.set_setpoint(5.);
+        let after = pid.update(12., secs(0.1));
+        assert!((after - before).abs() < 0.05, "{before} {after}");
+
+        pid.set_config(PidConfig {
+            kp: 0.3,
+            ..pid.config()
+        });
+        let after_gain = pid.update(12., secs(0.11));
+        assert!((after_gain - after).abs() < 0.05, "{after} {after_gain}");
+
+        // but the integral still gets there
+        for i in 12..200 {
+            pid.update(12., secs(i as f64 * 0.01));
+        }
+        assert_eq!(pid.output(), 1.);
+    }
+
+    #[test]
+    fn pid_anti_windup() {
+        let mut pid = Pid::new(PidConfig { ki: 1., ..config() });
+
+        // stuck far below the setpoint for a long time
+        for i in 0..1000 {
+            pid.update(13., secs(i as f64 * 0.01));
+        }
+        assert_eq!(pid.output(), 1.);
+        assert!(pid.integral < 0.25, "{}", pid.integral);
+
+        // comes off the limit as soon as the ball overshoots
+        let out = pid.update(9., secs(10.01));
+        assert!(out < 1., "{out}");
+    }
+}
@@ -0,0 +1,242 @@
+//! Stands in for the levitation firmware on the other end of the serial
+//! link: it decodes what the host sends, drives a simulated `Plant` with it
+//! in real time and replies with telemetry, so the serial side can be
+//! exercised end to end without the rig.
+//!
+//! Like the firmware, a `Command` sets the coil output directly, while a
+//! `Position` is fed to its own controller using the last `Setpoint` and
+//! `Gains`. Once heartbeats have started, the coil is turned off if they
+//! stop for `WATCHDOG_TIMEOUT`.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use serialport::SerialPort;
+
+use crate::control::{Pid, PidConfig};
+use crate::plant::{Plant, PlantConfig};
+use crate::serial::{self, Decoder, Faults, Packet, Telemetry};
+
+pub const TELEMETRY_INTERVAL: Duration = Duration::from_millis(10);
+pub const WATCHDOG_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub struct Emulator {
+    plant: Plant,
+    pid: Pid,
+    enabled: bool,
+    /// output while enabled, 0 to 1
+    duty: f32,
+    decoder: Decoder,
+    /// plant time of the last heartbeat
+    last_heartbeat: Option<Duration>,
+    last_step: Option<Instant>,
+    last_report: Option<Instant>,
+    /// how long the last `poll` took, reported as the loop time
+    loop_time: Duration,
+}
+
+impl Emulator {
+    /// The ball starts on the floor with the coil enabled but off
+    pub fn new(plant: PlantConfig, pid: PidConfig) -> Self {
+        Self {
+            plant: Plant::new(plant, plant.floor as f32),
+            pid: Pid::new(pid),
+            enabled: true,
+            duty: 0.,
+            decoder: Decoder::new(),
+            last_heartbeat: None,
+            last_step: None,
+            last_report: None,
+            loop_time: Duration::ZERO,
+        }
+    }
+
+    pub fn plant(&self) -> &Plant {
+        &self.plant
+    }
+
+    pub fn pid(&self) -> &Pid {
+        &self.pid
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Heartbeats stopped coming
+    pub fn starved(&self) -> bool {
+        self.last_heartbeat
+            .is_some_and(|last| self.plant.time().saturating_sub(last) > WATCHDOG_TIMEOUT)
+    }
+
+    /// Acts on a packet from the host
+    pub fn handle(&mut self, packet: Packet) {
+        match packet {
+            Packet::Command(command) => self.duty = command.clamp(0., 1.),
+            Packet::Position(gap) => self.duty = self.pid.update(gap.distance, self.plant.time()),
+            Packet::Setpoint(setpoint) => self.pid.set_setpoint(setpoint),
+            Packet::Gains { kp, ki, kd } => self.pid.set_config(PidConfig {
+                kp,
+                ki,
+                kd,
+                ..self.pid.config()
+            }),
+            Packet::Enable(on) => {
+                self.enabled = on;
+                if !on {
+                    self.duty = 0.;
+                    self.pid.reset();
+                }
+            }
+            Packet::Heartbeat(_) => self.last_heartbeat = Some(self.plant.time()),
+            // only ever sent the other way
+            Packet::Telemetry(_) => eprintln!("unexpected telemetry from the host"),
+        }
+    }
+
+    /// Advances the plant by `dt` with the current output
+    pub fn step(&mut self, dt: Duration) {
+        if self.starved() {
+            // not picked up again when heartbeats resume, it's stale by then
+            self.duty = 0.;
+            self.pid.reset();
+        }
+        let command = if self.enabled { self.duty } else { 0. };
+        self.plant.set_command(command);
+        self.plant.step(dt);
+    }
+
+    pub fn telemetry(&self) -> Telemetry {
+        Telemetry {
+            current: self.plant.current(),
+            duty: self.plant.command(),
+            loop_time: self.loop_time,
+            faults: if self.starved() {
+                Faults::WATCHDOG
+            } else {
+                Faults::default()
+            },
+        }
+    }
+
+    /// One pass of the firmware's main loop: waits up to the port's timeout
+    /// for bytes from the host, handles them, advances the plant to now and
+    /// sends telemetry every `TELEMETRY_INTERVAL`.
+    ///
+    /// An error means nobody has the other end of the port open, the
+    /// simulation carries on regardless.
+    pub fn poll(&mut self, port: &mut dyn SerialPort) -> io::Result<()> {
+        let mut buf = [0; 256];
+        let read = port.read(&mut buf);
+        let start = Instant::now();
+
+        let res = match read {
+            Ok(n) => {
+                for res in self.decoder.feed(&buf[..n]) {
+                    match res {
+                        Ok(packet) => self.handle(packet),
+                        Err(e) => eprintln!("serial: {e}"),
+                    }
+                }
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(()),
+            Err(e) => Err(e),
+        };
+
+        let now = Instant::now();
+        let dt = self.last_step.map_or(Duration::ZERO, |last| now - last);
+        self.step(dt);
+        self.last_step = Some(now);
+
+        // replies would only pile up in the pty without a host
+        res?;
+        if self
+            .last_report
+            .is_some_and(|last| now - last < TELEMETRY_INTERVAL)
+        {
+            return Ok(());
+        }
+        self.last_report = Some(now);
+        self.loop_time = start.elapsed();
+        serial::send(port, &Packet::Telemetry(self.telemetry()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Gap;
+
+    #[test]
+    fn emulator_packets() {
+        let mut emulator = Emulator::new(PlantConfig::default(), PidConfig::default());
+        let floor = emulator.plant().gap();
+
+        // the host's command pulls the ball off the floor
+        emulator.handle(Packet::Command(1.));
+        emulator.step(Duration::from_millis(50));
+        assert!(emulator.telemetry().current > 0.5);
+        assert_eq!(emulator.telemetry().duty, 1.);
+        assert!(emulator.plant().gap() < floor);
+
+        // disabled, the coil stays off whatever is sent
+        emulator.handle(Packet::Enable(false));
+        emulator.handle(Packet::Command(0.7));
+        emulator.step(Duration::from_millis(10));
+        assert_eq!(emulator.telemetry().duty, 0.);
+
+        // positions go through the firmware's own controller
+        emulator.handle(Packet::Enable(true));
+        emulator.handle(Packet::Setpoint(20.));
+        emulator.handle(Packet::Gains {
+            kp: 0.5,
+            ki: 0.,
+            kd: 0.,
+        });
+        let config = emulator.pid().config();
+        assert_eq!((config.setpoint, config.kp, config.ki), (20., 0.5, 0.));
+
+        emulator.handle(Packet::Position(Gap {
+            distance: 20.5,
+            offset: 0.,
+        }));
+        emulator.step(Duration::from_millis(1));
+        let expected = config.bias + 0.25;
+        assert!((emulator.telemetry().duty - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn emulator_watchdog() {
+        let mut emulator = Emulator::new(PlantConfig::default(), PidConfig::default());
+        let tick = Duration::from_millis(100);
+
+        // without heartbeats there's no watchdog
+        emulator.handle(Packet::Command(0.5));
+        emulator.step(WATCHDOG_TIMEOUT * 2);
+        assert_eq!(emulator.telemetry().duty, 0.5);
+
+        emulator.handle(Packet::Heartbeat(1));
+        for _ in 0..5 {
+            emulator.step(tick);
+        }
+        assert!(!emulator.starved());
+        assert_eq!(emulator.telemetry().duty, 0.5);
+
+        emulator.step(tick);
+        assert!(emulator.starved());
+        emulator.step(tick);
+        let telemetry = emulator.telemetry();
+        assert_eq!(telemetry.duty, 0.);
+        assert_eq!(telemetry.faults, Faults::WATCHDOG);
+
+        // back, but the coil waits for a new command
+        emulator.handle(Packet::Heartbeat(2));
+        emulator.step(tick);
+        assert_eq!(emulator.telemetry().duty, 0.);
+        assert!(emulator.telemetry().faults.is_empty());
+        emulator.handle(Packet::Command(0.4));
+        emulator.step(tick);
+        assert_eq!(emulator.telemetry().duty, 0.4);
+    }
+}
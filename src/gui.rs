@@ -1,5 +1,11 @@
+use crate::color::{Color, Hsv, HUE_MAX};
+use crate::control::PidConfig;
+use crate::tracker::{BlobParams, Localisation, Selection};
 use opencv::highgui as cv_gui;
-use std::sync::{mpsc, Arc, Mutex};
+use std::sync::{
+    atomic::{AtomicU32, AtomicU8, Ordering::SeqCst},
+    mpsc, Arc, Mutex,
+};
 
 pub const WINDOW_NAME: &str = "Magnetic Levitation";
 
@@ -10,6 +16,16 @@ pub enum Message {
     SelectMagnet,
     ToggleRaw(bool),
     SaveImg,
+    SaveConfig,
+    /// the next two clicks are `Reference mm` apart
+    CalibrateScale,
+    /// the detected ball is `Reference mm` wide
+    CalibrateDiameter,
+    /// the next four clicks are the corners of a rectangle, clockwise from its top left
+    CalibrateTilt,
+    /// keep the chessboard in the current frame as a lens calibration view
+    CaptureChessboard,
+    CalibrateLens,
 }
 
 pub type Sender = Arc<Mutex<mpsc::Sender<Message>>>;
@@ -34,40 +50,275 @@ pub fn raw_image_callback(tx: Sender) -> cv_gui::ButtonCallback {
     }))
 }
 
-//pub fn create_tolerance_trackbars(hsv: Arc<Mutex<Hsv>>) {
-//    let create_trackbar = |name, max_val, closure| {
-//        cv_gui::create_trackbar(name, WINDOW_NAME, None, max_val, Some(closure)).unwrap();
-//    };
-//
-//    let hsv1 = hsv.clone();
-//    create_trackbar(
-//        "H Tolerance",
-//        255,
-//        Box::new(move |val| hsv1.lock().unwrap().h = val as u8),
-//    );
-//    let hsv1 = hsv.clone();
-//    create_trackbar(
-//        "S Tolerance",
-//        255,
-//        Box::new(move |val| hsv1.lock().unwrap().s = val as u8),
-//    );
-//    let hsv1 = hsv.clone();
-//    create_trackbar(
-//        "V Tolerance",
-//        255,
-//        Box::new(move |val| hsv1.lock().unwrap().v = val as u8),
-//    );
-//}
-use std::sync::atomic::{AtomicU8, Ordering::SeqCst};
-pub fn create_tolerance_trackbar(tol: Arc<AtomicU8>) {
+pub fn create_tolerance_trackbars_rgb(rgb: Arc<Mutex<Color>>) {
+    let create_trackbar = |name, max_val, closure| {
+        cv_gui::create_trackbar(name, WINDOW_NAME, None, max_val, Some(closure)).unwrap();
+    };
+
+    let rgb1 = rgb.clone();
+    create_trackbar(
+        "R Tolerance",
+        255,
+        Box::new(move |val| *rgb1.lock().unwrap().r_mut() = val as u8),
+    );
+    let rgb1 = rgb.clone();
+    create_trackbar(
+        "G Tolerance",
+        255,
+        Box::new(move |val| *rgb1.lock().unwrap().g_mut() = val as u8),
+    );
+    let rgb1 = rgb.clone();
+    create_trackbar(
+        "B Tolerance",
+        255,
+        Box::new(move |val| *rgb1.lock().unwrap().b_mut() = val as u8),
+    );
+}
+
+pub fn create_tolerance_trackbars(hsv: Arc<Mutex<Hsv>>) {
+    let create_trackbar = |name, max_val, closure| {
+        cv_gui::create_trackbar(name, WINDOW_NAME, None, max_val, Some(closure)).unwrap();
+    };
+
+    // hue wraps around, so half the range already covers every hue
+    let hsv1 = hsv.clone();
+    create_trackbar(
+        "H Tolerance",
+        (HUE_MAX / 2) as i32,
+        Box::new(move |val| hsv1.lock().unwrap().h = val as u8),
+    );
+    let hsv1 = hsv.clone();
+    create_trackbar(
+        "S Tolerance",
+        255,
+        Box::new(move |val| hsv1.lock().unwrap().s = val as u8),
+    );
+    let hsv1 = hsv.clone();
+    create_trackbar(
+        "V/L Tolerance",
+        255,
+        Box::new(move |val| hsv1.lock().unwrap().v = val as u8),
+    );
+}
+
+/// 0: RGB, 1: HSV, 2: HSL
+pub fn create_color_space_trackbar(space: Arc<AtomicU8>) {
+    cv_gui::create_trackbar(
+        "Color Space",
+        WINDOW_NAME,
+        None,
+        2,
+        Some(Box::new(move |val| space.store(val as u8, SeqCst))),
+    )
+    .unwrap();
+}
+
+// trackbar picking one of `count` options by index
+fn create_index_trackbar(name: &str, count: usize, index: Arc<AtomicU8>) {
+    let initial = index.load(SeqCst) as i32;
+
+    cv_gui::create_trackbar(
+        name,
+        WINDOW_NAME,
+        None,
+        count as i32 - 1,
+        Some(Box::new(move |val| index.store(val as u8, SeqCst))),
+    )
+    .unwrap();
+    cv_gui::set_trackbar_pos(name, WINDOW_NAME, initial).unwrap();
+}
+
+/// Index into `Selection::ALL`
+pub fn create_selection_trackbar(selection: Arc<AtomicU8>) {
+    create_index_trackbar("Blob Selection", Selection::ALL.len(), selection);
+}
+
+/// Index into `Localisation::ALL`
+pub fn create_localisation_trackbar(localisation: Arc<AtomicU8>) {
+    create_index_trackbar("Localisation", Localisation::ALL.len(), localisation);
+}
+
+// trackbar at `pos(config)`, moving it calls `set`. A position the config
+// is already at leaves it alone, so the initial one doesn't round it to
+// the trackbar's steps.
+fn create_config_trackbar<T: Send + 'static>(
+    name: &str,
+    max_val: i32,
+    config: &Arc<Mutex<T>>,
+    pos: fn(&T) -> i32,
+    set: fn(&mut T, i32),
+) {
+    let initial = pos(&config.lock().unwrap());
+
+    let config = config.clone();
     cv_gui::create_trackbar(
-        "Tolerance",
+        name,
         WINDOW_NAME,
         None,
+        max_val,
+        Some(Box::new(move |val| {
+            let mut c = config.lock().unwrap();
+            if pos(&c) != val {
+                set(&mut c, val);
+            }
+        })),
+    )
+    .unwrap();
+    cv_gui::set_trackbar_pos(name, WINDOW_NAME, initial).unwrap();
+}
+
+fn percent(enabled: bool, val: f32) -> i32 {
+    if enabled {
+        (val * 100.).round() as i32
+    } else {
+        0
+    }
+}
+
+/// The detector's thresholds, and filters where a position of 0 disables
+/// the corresponding filter
+pub fn create_blob_trackbars(params: Arc<Mutex<BlobParams>>) {
+    // nothing is found unless min < max
+    create_config_trackbar(
+        "Min Threshold",
         255,
-        Some(Box::new(move |val| tol.store(val as u8, SeqCst))),
+        &params,
+        |p| p.min_threshold.round() as i32,
+        |p, val| p.min_threshold = val as f32,
+    );
+    create_config_trackbar(
+        "Max Threshold",
+        255,
+        &params,
+        |p| p.max_threshold.round() as i32,
+        |p, val| p.max_threshold = val as f32,
+    );
+    create_config_trackbar(
+        "Threshold Step",
+        255,
+        &params,
+        |p| p.threshold_step.round() as i32,
+        // a step of 0 would never get to the max
+        |p, val| p.threshold_step = val.max(1) as f32,
+    );
+
+    create_config_trackbar(
+        "Min Area",
+        5000,
+        &params,
+        |p| {
+            if p.filter_by_area {
+                p.min_area as i32
+            } else {
+                0
+            }
+        },
+        |p, val| {
+            p.min_area = val as f32;
+            if !p.filter_by_area {
+                // the Max Area slider shows 0 while the filter is off, and so should its bound
+                p.max_area = f32::INFINITY;
+            }
+            p.filter_by_area = val > 0 || p.max_area.is_finite();
+        },
+    );
+    create_config_trackbar(
+        "Max Area",
+        50000,
+        &params,
+        |p| {
+            if p.filter_by_area && p.max_area.is_finite() {
+                p.max_area as i32
+            } else {
+                0
+            }
+        },
+        |p, val| {
+            p.max_area = if val > 0 { val as f32 } else { f32::INFINITY };
+            if !p.filter_by_area {
+                p.min_area = 0.;
+            }
+            p.filter_by_area = val > 0 || p.min_area > 0.;
+        },
+    );
+    create_config_trackbar(
+        "Min Circularity %",
+        100,
+        &params,
+        |p| percent(p.filter_by_circularity, p.min_circularity),
+        |p, val| {
+            p.min_circularity = val as f32 / 100.;
+            p.filter_by_circularity = val > 0;
+        },
+    );
+    create_config_trackbar(
+        "Min Convexity %",
+        100,
+        &params,
+        |p| percent(p.filter_by_convexity, p.min_convexity),
+        |p, val| {
+            p.min_convexity = val as f32 / 100.;
+            p.filter_by_convexity = val > 0;
+        },
+    );
+    create_config_trackbar(
+        "Min Inertia %",
+        100,
+        &params,
+        |p| percent(p.filter_by_inertia, p.min_inertia_ratio),
+        |p, val| {
+            p.min_inertia_ratio = val as f32 / 100.;
+            p.filter_by_inertia = val > 0;
+        },
+    );
+}
+
+/// Gains scaled to whole steps fine enough for the defaults,
+/// the setpoint in tenths of a mm
+pub fn create_pid_trackbars(config: Arc<Mutex<PidConfig>>) {
+    create_config_trackbar(
+        "Kp x1000",
+        1000,
+        &config,
+        |c| (c.kp * 1e3).round() as i32,
+        |c, val| c.kp = val as f32 / 1e3,
+    );
+    create_config_trackbar(
+        "Ki x1000",
+        1000,
+        &config,
+        |c| (c.ki * 1e3).round() as i32,
+        |c, val| c.ki = val as f32 / 1e3,
+    );
+    create_config_trackbar(
+        "Kd x100000",
+        1000,
+        &config,
+        |c| (c.kd * 1e5).round() as i32,
+        |c, val| c.kd = val as f32 / 1e5,
+    );
+    create_config_trackbar(
+        "Setpoint mm x10",
+        500,
+        &config,
+        |c| (c.setpoint * 10.).round() as i32,
+        |c, val| c.setpoint = val as f32 / 10.,
+    );
+}
+
+/// Known length used by the calibration buttons
+pub fn create_reference_trackbar(mm: Arc<AtomicU32>) {
+    let initial = mm.load(SeqCst) as i32;
+
+    cv_gui::create_trackbar(
+        "Reference mm",
+        WINDOW_NAME,
+        None,
+        500,
+        Some(Box::new(move |val| mm.store(val as u32, SeqCst))),
     )
     .unwrap();
+    cv_gui::set_trackbar_pos("Reference mm", WINDOW_NAME, initial).unwrap();
 }
 
 pub fn create_buttons(tx: Sender) {
@@ -102,28 +353,52 @@ pub fn create_buttons(tx: Sender) {
         false,
     )
     .unwrap();
+
+    cv_gui::create_button(
+        "Save Config",
+        create_button_callback(tx.clone(), Message::SaveConfig),
+        cv_gui::QT_PUSH_BUTTON,
+        false,
+    )
+    .unwrap();
+
+    cv_gui::create_button(
+        "Calibrate Scale",
+        create_button_callback(tx.clone(), Message::CalibrateScale),
+        cv_gui::QT_PUSH_BUTTON,
+        false,
+    )
+    .unwrap();
+
+    cv_gui::create_button(
+        "Calibrate Diameter",
+        create_button_callback(tx.clone(), Message::CalibrateDiameter),
+        cv_gui::QT_PUSH_BUTTON,
+        false,
+    )
+    .unwrap();
+
+    cv_gui::create_button(
+        "Calibrate Tilt",
+        create_button_callback(tx.clone(), Message::CalibrateTilt),
+        cv_gui::QT_PUSH_BUTTON,
+        false,
+    )
+    .unwrap();
+
+    cv_gui::create_button(
+        "Capture Chessboard",
+        create_button_callback(tx.clone(), Message::CaptureChessboard),
+        cv_gui::QT_PUSH_BUTTON,
+        false,
+    )
+    .unwrap();
+
+    cv_gui::create_button(
+        "Calibrate Lens",
+        create_button_callback(tx.clone(), Message::CalibrateLens),
+        cv_gui::QT_PUSH_BUTTON,
+        false,
+    )
+    .unwrap();
 }
-//pub fn create_tolerance_trackbars_rgb(rgb: Arc<Mutex<crate::color::Color>>) {
-//    let create_trackbar = |name, max_val, closure| {
-//        cv_gui::create_trackbar(name, WINDOW_NAME, None, max_val, Some(closure)).unwrap();
-//    };
-//
-//    let rgb1 = rgb.clone();
-//    create_trackbar(
-//        "R Tolerance",
-//        255,
-//        Box::new(move |val| *rgb1.lock().unwrap().r_mut() = val as u8),
-//    );
-//    let rgb1 = rgb.clone();
-//    create_trackbar(
-//        "G Tolerance",
-//        255,
-//        Box::new(move |val| *rgb1.lock().unwrap().g_mut() = val as u8),
-//    );
-//    let rgb1 = rgb.clone();
-//    create_trackbar(
-//        "B Tolerance",
-//        255,
-//        Box::new(move |val| *rgb1.lock().unwrap().b_mut() = val as u8),
-//    );
-//}
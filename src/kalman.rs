@@ -0,0 +1,394 @@
+//! Kalman filter smoothing the ball's position and estimating its velocity.
+//!
+//! The axes are filtered independently, each with a position / velocity /
+//! acceleration state. With `Model::ConstantVelocity` the acceleration is
+//! pinned at zero, so the same 3x3 math covers both models.
+
+use std::time::Duration;
+
+use crate::Ball;
+
+type Vector = [f64; 3];
+type Matrix = [[f64; 3]; 3];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Model {
+    #[default]
+    ConstantVelocity,
+    ConstantAcceleration,
+}
+
+crate::keywords!(
+    Model,
+    [
+        ConstantVelocity => "constant_velocity",
+        ConstantAcceleration => "constant_acceleration",
+    ]
+);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KalmanConfig {
+    pub model: Model,
+    /// Intensity of the random acceleration (constant velocity model)
+    /// or jerk (constant acceleration model) the ball is assumed to undergo.
+    /// Higher follows changes faster, lower smooths more.
+    pub process_noise: f32,
+    /// Standard deviation of the measured position, in pixels
+    pub measurement_noise: f32,
+    /// How long to keep predicting without a detection before the ball
+    /// counts as lost, in seconds
+    pub max_prediction: f32,
+}
+
+impl Default for KalmanConfig {
+    fn default() -> Self {
+        Self {
+            model: Model::ConstantVelocity,
+            process_noise: 5000.,
+            measurement_noise: 1.,
+            max_prediction: 0.25,
+        }
+    }
+}
+
+crate::section!(
+    KalmanConfig,
+    "kalman",
+    [model, process_noise, measurement_noise, max_prediction]
+);
+
+/// Filtered ball state, in pixels and seconds
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct BallState {
+    pub x: f32,
+    pub y: f32,
+    pub vx: f32,
+    pub vy: f32,
+    pub ax: f32,
+    pub ay: f32,
+    /// Covariance of (position, velocity, acceleration), for x and y
+    pub covariance: [[[f32; 3]; 3]; 2],
+    pub timestamp: Duration,
+    /// No measurement was available at `timestamp`
+    pub predicted: bool,
+}
+
+// variance of the initial velocity / acceleration guesses, large enough to
+// let the first few measurements decide
+const INITIAL_VELOCITY_VARIANCE: f64 = 1e6;
+const INITIAL_ACCELERATION_VARIANCE: f64 = 1e8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Axis {
+    x: Vector,
+    p: Matrix,
+}
+
+impl Axis {
+    fn new(position: f64, config: &KalmanConfig) -> Self {
+        let mut p = [[0.; 3]; 3];
+        p[0][0] = (config.measurement_noise as f64).powi(2);
+        p[1][1] = INITIAL_VELOCITY_VARIANCE;
+        if config.model == Model::ConstantAcceleration {
+            p[2][2] = INITIAL_ACCELERATION_VARIANCE;
+        }
+
+        Self {
+            x: [position, 0., 0.],
+            p,
+        }
+    }
+
+    fn predict(&mut self, dt: f64, config: &KalmanConfig) {
+        let f = transition(dt, config.model);
+        let q = process_noise(dt, config);
+
+        self.x = mul_vec(&f, &self.x);
+        self.p = add(&mul(&mul(&f, &self.p), &transpose(&f)), &q);
+    }
+
+    fn update(&mut self, measurement: f64, config: &KalmanConfig) {
+        let r = (config.measurement_noise as f64).powi(2);
+
+        // H = [1, 0, 0]
+        let s = self.p[0][0] + r;
+        let k = [self.p[0][0] / s, self.p[1][0] / s, self.p[2][0] / s];
+        let residual = measurement - self.x[0];
+
+        for (x, k) in self.x.iter_mut().zip(k) {
+            *x += k * residual;
+        }
+
+        let p0 = self.p[0];
+        for (row, k) in self.p.iter_mut().zip(k) {
+            for (p, p0) in row.iter_mut().zip(p0) {
+                *p -= k * p0;
+            }
+        }
+    }
+}
+
+fn transition(dt: f64, model: Model) -> Matrix {
+    let a = match model {
+        Model::ConstantVelocity => 0.,
+        Model::ConstantAcceleration => 1.,
+    };
+
+    [[1., dt, a * dt * dt / 2.], [0., 1., a * dt], [0., 0., a]]
+}
+
+// discretised continuous white noise on the highest derivative
+fn process_noise(dt: f64, config: &KalmanConfig) -> Matrix {
+    let q = config.process_noise as f64;
+    let dt2 = dt * dt;
+    let dt3 = dt2 * dt;
+
+    match config.model {
+        Model::ConstantVelocity => [
+            [q * dt3 / 3., q * dt2 / 2., 0.],
+            [q * dt2 / 2., q * dt, 0.],
+            [0., 0., 0.],
+        ],
+        Model::ConstantAcceleration => {
+            let dt4 = dt3 * dt;
+            let dt5 = dt4 * dt;
+            [
+                [q * dt5 / 20., q * dt4 / 8., q * dt3 / 6.],
+                [q * dt4 / 8., q * dt3 / 3., q * dt2 / 2.],
+                [q * dt3 / 6., q * dt2 / 2., q * dt],
+            ]
+        }
+    }
+}
+
+fn mul(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut out = [[0.; 3]; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            out[i][j] = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    out
+}
+
+fn mul_vec(a: &Matrix, v: &Vector) -> Vector {
+    let mut out = [0.; 3];
+    for i in 0..3 {
+        out[i] = (0..3).map(|k| a[i][k] * v[k]).sum();
+    }
+    out
+}
+
+fn add(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut out = *a;
+    for i in 0..3 {
+        for j in 0..3 {
+            out[i][j] += b[i][j];
+        }
+    }
+    out
+}
+
+fn transpose(a: &Matrix) -> Matrix {
+    let mut out = [[0.; 3]; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            out[i][j] = a[j][i];
+        }
+    }
+    out
+}
+
+/// Tracks one ball across frames.
+///
+/// Call `update` for every detection and `predict` for every frame the ball
+/// wasn't found in, both return the current estimate.
+#[derive(Debug, Clone)]
+pub struct Kalman {
+    config: KalmanConfig,
+    axes: Option<[Axis; 2]>,
+    timestamp: Duration,
+    last_measurement: Duration,
+}
+
+impl Kalman {
+    pub fn new(config: KalmanConfig) -> Self {
+        Self {
+            config,
+            axes: None,
+            timestamp: Duration::ZERO,
+            last_measurement: Duration::ZERO,
+        }
+    }
+
+    pub fn config(&self) -> KalmanConfig {
+        self.config
+    }
+
+    /// Changing the model restarts the filter from the next measurement
+    pub fn set_config(&mut self, config: KalmanConfig) {
+        if config.model != self.config.model {
+            self.reset();
+        }
+        self.config = config;
+    }
+
+    pub fn reset(&mut self) {
+        self.axes = None;
+    }
+
+    pub fn update(&mut self, ball: &Ball) -> BallState {
+        let measurement = [ball.x as f64, ball.y as f64];
+
+        match &mut self.axes {
+            Some(axes) => {
+                let dt = ball.timestamp.saturating_sub(self.timestamp).as_secs_f64();
+                for (axis, z) in axes.iter_mut().zip(measurement) {
+                    if dt > 0. {
+                        axis.predict(dt, &self.config);
+                    }
+                    axis.update(z, &self.config);
+                }
+            }
+            None => self.axes = Some(measurement.map(|z| Axis::new(z, &self.config))),
+        }
+
+        self.timestamp = self.timestamp.max(ball.timestamp);
+        self.last_measurement = self.timestamp;
+        self.state().unwrap()
+    }
+
+    /// Estimate at `timestamp` without a measurement.
+    /// `None` if there was never one, or the ball has been missing
+    /// for longer than `max_prediction` (which also resets the filter).
+    pub fn predict(&mut self, timestamp: Duration) -> Option<BallState> {
+        let axes = self.axes.as_mut()?;
+
+        let missing = timestamp.saturating_sub(self.last_measurement);
+        if missing.as_secs_f32() > self.config.max_prediction {
+            self.reset();
+            return None;
+        }
+
+        let dt = timestamp.saturating_sub(self.timestamp).as_secs_f64();
+        if dt > 0. {
+            for axis in axes.iter_mut() {
+                axis.predict(dt, &self.config);
+            }
+            self.timestamp = timestamp;
+        }
+
+        self.state()
+    }
+
+    /// Where the ball is expected to be at `timestamp`, without changing the state
+    pub fn position_at(&self, timestamp: Duration) -> Option<(f32, f32)> {
+        let axes = self.axes.as_ref()?;
+        let dt = timestamp.saturating_sub(self.timestamp).as_secs_f64();
+
+        let [x, y] = axes.map(|axis| mul_vec(&transition(dt, self.config.model), &axis.x)[0]);
+        Some((x as f32, y as f32))
+    }
+
+    /// Latest estimate, `None` until the first measurement
+    pub fn state(&self) -> Option<BallState> {
+        let [x, y] = self.axes?;
+        let covariance = |axis: Axis| axis.p.map(|row| row.map(|v| v as f32));
+
+        Some(BallState {
+            x: x.x[0] as f32,
+            y: y.x[0] as f32,
+            vx: x.x[1] as f32,
+            vy: y.x[1] as f32,
+            ax: x.x[2] as f32,
+            ay: y.x[2] as f32,
+            covariance: [covariance(x), covariance(y)],
+            timestamp: self.timestamp,
+            predicted: self.timestamp > self.last_measurement,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ball(x: f32, y: f32, t: f64) -> Ball {
+        Ball {
+            x,
+            y,
+            timestamp: Duration::from_secs_f64(t),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn kalman_constant_velocity() {
+        let mut kalman = Kalman::new(KalmanConfig::default());
+        assert_eq!(kalman.state(), None);
+        assert_eq!(kalman.predict(Duration::ZERO), None);
+
+        let dt = 1. / 60.;
+        let mut state = BallState::default();
+        for i in 0..120 {
+            let t = i as f64 * dt;
+            state = kalman.update(&ball(100. + 30. * t as f32, 200. - 60. * t as f32, t));
+        }
+        assert!((state.vx - 30.).abs() < 1., "{state:?}");
+        assert!((state.vy + 60.).abs() < 1., "{state:?}");
+        assert!(!state.predicted);
+
+        // coasts through a missed detection
+        let t = 120. * dt;
+        let (_, expected_y) = kalman.position_at(Duration::from_secs_f64(t)).unwrap();
+        let predicted = kalman.predict(Duration::from_secs_f64(t)).unwrap();
+        assert!(predicted.predicted);
+        assert_eq!(predicted.y, expected_y);
+        assert!(
+            (predicted.y - (200. - 60. * t as f32)).abs() < 0.5,
+            "{predicted:?}"
+        );
+        assert!(predicted.covariance[1][0][0] > state.covariance[1][0][0]);
+
+        // until it has been missing for too long
+        assert_eq!(kalman.predict(Duration::from_secs_f64(t + 1.)), None);
+        assert_eq!(kalman.state(), None);
+    }
+
+    #[test]
+    fn kalman_constant_acceleration() {
+        let config = KalmanConfig {
+            model: Model::ConstantAcceleration,
+            ..Default::default()
+        };
+        let mut kalman = Kalman::new(config);
+
+        // falling from rest
+        let dt = 1. / 100.;
+        let mut state = BallState::default();
+        for i in 0..200 {
+            let t = i as f64 * dt;
+            state = kalman.update(&ball(0., 0.5 * 400. * (t * t) as f32, t));
+        }
+        assert!((state.ay - 400.).abs() < 10., "{state:?}");
+        assert!((state.vy - 400. * 199. * dt as f32).abs() < 5., "{state:?}");
+    }
+
+    #[test]
+    fn kalman_smooths_noise() {
+        let mut kalman = Kalman::new(KalmanConfig {
+            measurement_noise: 2.,
+            process_noise: 100.,
+            ..Default::default()
+        });
+
+        // deterministic +-2px alternating noise around a still ball
+        let mut state = BallState::default();
+        for i in 0..100 {
+            let noise = if i % 2 == 0 { 2. } else { -2. };
+            state = kalman.update(&ball(50. + noise, 50., i as f64 / 60.));
+        }
+        assert!((state.x - 50.).abs() < 1., "{state:?}");
+        assert!(state.vx.abs() < 30., "{state:?}");
+    }
+}
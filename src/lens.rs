@@ -0,0 +1,348 @@
+//! Correcting lens distortion, calibrated from views of a chessboard.
+//!
+//! Capture the printed chessboard from several angles and positions,
+//! especially near the edges of the frame where distortion is worst,
+//! then `calibrate` to get a `LensModel`.
+
+use cv::{
+    calib3d,
+    core::{
+        FileStorage, FileStorage_Mode, Point2f, Point3f, Size, TermCriteria, TermCriteria_Type,
+        Vector,
+    },
+    prelude::*,
+};
+use opencv as cv;
+
+use crate::Ball;
+
+/// Need at least this many views for a usable calibration
+pub const MIN_VIEWS: usize = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LensConfig {
+    /// Where the calibration is saved, and loaded from on startup
+    pub file: String,
+    /// Undistort whole frames before tracking instead of only the ball's
+    /// position, slower but the blob keeps its real shape
+    pub undistort_frames: bool,
+    /// Inner corners of the chessboard, i.e. squares - 1
+    pub chessboard_cols: i32,
+    pub chessboard_rows: i32,
+    /// Side of a chessboard square, in mm
+    pub square_size: f32,
+}
+
+impl Default for LensConfig {
+    fn default() -> Self {
+        Self {
+            file: "lens.yml".to_string(),
+            undistort_frames: false,
+            chessboard_cols: 9,
+            chessboard_rows: 6,
+            square_size: 25.,
+        }
+    }
+}
+
+crate::section!(
+    LensConfig,
+    "lens",
+    [
+        file,
+        undistort_frames,
+        chessboard_cols,
+        chessboard_rows,
+        square_size,
+    ]
+);
+
+fn criteria() -> TermCriteria {
+    TermCriteria::new(
+        TermCriteria_Type::COUNT as i32 + TermCriteria_Type::EPS as i32,
+        30,
+        1e-3,
+    )
+    .unwrap()
+}
+
+/// Collects chessboard views from the camera
+pub struct ChessboardCalibrator {
+    pattern: Size,
+    board: Vector<Point3f>,
+    object_points: Vector<Vector<Point3f>>,
+    image_points: Vector<Vector<Point2f>>,
+    image_size: Size,
+    gray: Mat,
+}
+
+impl ChessboardCalibrator {
+    pub fn new(config: &LensConfig) -> Self {
+        let pattern = Size::new(config.chessboard_cols, config.chessboard_rows);
+
+        // the board lies flat at z = 0
+        let mut board = Vector::new();
+        for row in 0..pattern.height {
+            for col in 0..pattern.width {
+                board.push(Point3f::new(
+                    col as f32 * config.square_size,
+                    row as f32 * config.square_size,
+                    0.,
+                ));
+            }
+        }
+
+        Self {
+            pattern,
+            board,
+            object_points: Vector::new(),
+            image_points: Vector::new(),
+            image_size: Size::default(),
+            gray: Mat::default(),
+        }
+    }
+
+    /// Number of views captured so far
+    pub fn views(&self) -> usize {
+        self.image_points.len()
+    }
+
+    /// Looks for the whole chessboard in `frame`, keeping it as a view if found
+    pub fn capture(&mut self, frame: &Mat) -> bool {
+        cv::imgproc::cvt_color(frame, &mut self.gray, cv::imgproc::COLOR_BGR2GRAY, 0).unwrap();
+
+        let mut corners = Vector::<Point2f>::new();
+        let found = calib3d::find_chessboard_corners(
+            &self.gray,
+            self.pattern,
+            &mut corners,
+            calib3d::CALIB_CB_ADAPTIVE_THRESH | calib3d::CALIB_CB_NORMALIZE_IMAGE,
+        )
+        .unwrap();
+        if !found {
+            return false;
+        }
+
+        cv::imgproc::corner_sub_pix(
+            &self.gray,
+            &mut corners,
+            Size::new(11, 11),
+            Size::new(-1, -1),
+            criteria(),
+        )
+        .unwrap();
+
+        self.image_size = frame.size().unwrap();
+        self.object_points.push(self.board.clone());
+        self.image_points.push(corners);
+        true
+    }
+
+    /// `None` until there are `MIN_VIEWS` views
+    pub fn calibrate(&self) -> Option<LensModel> {
+        if self.views() < MIN_VIEWS {
+            return None;
+        }
+
+        let mut camera_matrix = Mat::default();
+        let mut dist_coeffs = Mat::default();
+        let rms = calib3d::calibrate_camera(
+            &self.object_points,
+            &self.image_points,
+            self.image_size,
+            &mut camera_matrix,
+            &mut dist_coeffs,
+            &mut Vector::<Mat>::new(),
+            &mut Vector::<Mat>::new(),
+            0,
+            criteria(),
+        )
+        .unwrap();
+
+        Some(LensModel::new(camera_matrix, dist_coeffs, rms))
+    }
+}
+
+/// Camera intrinsics and distortion coefficients
+pub struct LensModel {
+    camera_matrix: Mat,
+    dist_coeffs: Mat,
+    /// reprojection error of the calibration, in pixels
+    pub rms: f64,
+
+    // undistortion maps for `undistort_frame`, built for the first frame size
+    map_size: Size,
+    map1: Mat,
+    map2: Mat,
+}
+
+impl LensModel {
+    fn new(camera_matrix: Mat, dist_coeffs: Mat, rms: f64) -> Self {
+        Self {
+            camera_matrix,
+            dist_coeffs,
+            rms,
+            map_size: Size::default(),
+            map1: Mat::default(),
+            map2: Mat::default(),
+        }
+    }
+
+    pub fn load(path: &str) -> cv::Result<Self> {
+        let fs = FileStorage::new(path, FileStorage_Mode::READ as i32, "")?;
+        if !fs.is_opened()? {
+            return Err(cv::Error::new(
+                cv::core::StsError,
+                format!("could not open {path}"),
+            ));
+        }
+
+        Ok(Self::new(
+            fs.get("camera_matrix")?.mat()?,
+            fs.get("dist_coeffs")?.mat()?,
+            fs.get("rms")?.to_f64()?,
+        ))
+    }
+
+    pub fn save(&self, path: &str) -> cv::Result<()> {
+        let mut fs = FileStorage::new(path, FileStorage_Mode::WRITE as i32, "")?;
+        fs.write_mat("camera_matrix", &self.camera_matrix)?;
+        fs.write_mat("dist_coeffs", &self.dist_coeffs)?;
+        fs.write_f64("rms", self.rms)?;
+        fs.release()
+    }
+
+    pub fn undistort_frame(&mut self, src: &Mat, dst: &mut Mat) {
+        let size = src.size().unwrap();
+        if size != self.map_size {
+            calib3d::init_undistort_rectify_map(
+                &self.camera_matrix,
+                &self.dist_coeffs,
+                &Mat::default(),
+                &self.camera_matrix,
+                size,
+                cv::core::CV_16SC2,
+                &mut self.map1,
+                &mut self.map2,
+            )
+            .unwrap();
+            self.map_size = size;
+        }
+
+        cv::imgproc::remap(
+            src,
+            dst,
+            &self.map1,
+            &self.map2,
+            cv::imgproc::INTER_LINEAR,
+            cv::core::BORDER_CONSTANT,
+            cv::core::Scalar::default(),
+        )
+        .unwrap();
+    }
+
+    /// Where `point` would be without distortion, in pixels
+    pub fn undistort_point(&self, (x, y): (f32, f32)) -> (f32, f32) {
+        let src = Vector::<Point2f>::from_iter([Point2f::new(x, y)]);
+        let mut dst = Vector::<Point2f>::new();
+
+        // projecting back with the camera matrix keeps the result in pixels
+        calib3d::undistort_points(
+            &src,
+            &mut dst,
+            &self.camera_matrix,
+            &self.dist_coeffs,
+            &Mat::default(),
+            &self.camera_matrix,
+        )
+        .unwrap();
+
+        let p = dst.get(0).unwrap();
+        (p.x, p.y)
+    }
+
+    /// Inverse of `undistort_point`, where a point without distortion
+    /// shows up in the frame
+    pub fn distort_point(&self, (x, y): (f32, f32)) -> (f32, f32) {
+        let k = |row, col| *self.camera_matrix.at_2d::<f64>(row, col).unwrap();
+        let (fx, fy, cx, cy) = (k(0, 0), k(1, 1), k(0, 2), k(1, 2));
+
+        // the point on the z = 1 plane in front of the camera, seen through the lens
+        let object = Vector::<Point3f>::from_iter([Point3f::new(
+            ((x as f64 - cx) / fx) as f32,
+            ((y as f64 - cy) / fy) as f32,
+            1.,
+        )]);
+        let no_motion = Vector::<f64>::from_iter([0., 0., 0.]);
+        let mut image = Vector::<Point2f>::new();
+        calib3d::project_points(
+            &object,
+            &no_motion,
+            &no_motion,
+            &self.camera_matrix,
+            &self.dist_coeffs,
+            &mut image,
+            &mut cv::core::no_array(),
+            0.,
+        )
+        .unwrap();
+
+        let p = image.get(0).unwrap();
+        (p.x, p.y)
+    }
+
+    pub fn undistort_ball(&self, ball: &Ball) -> Ball {
+        let (x, y) = self.undistort_point((ball.x, ball.y));
+        Ball { x, y, ..*ball }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model(dist_coeffs: &[f64]) -> LensModel {
+        let camera_matrix =
+            Mat::from_slice_2d(&[[500., 0., 320.], [0., 500., 240.], [0., 0., 1.]]).unwrap();
+        LensModel::new(camera_matrix, Mat::from_slice(dist_coeffs).unwrap(), 0.)
+    }
+
+    fn near((ax, ay): (f32, f32), (bx, by): (f32, f32), tolerance: f32) -> bool {
+        (ax - bx).hypot(ay - by) < tolerance
+    }
+
+    const POINTS: [(f32, f32); 5] = [
+        (320., 240.),
+        (50., 40.),
+        (600., 50.5),
+        (30.25, 450.),
+        (610., 430.75),
+    ];
+
+    #[test]
+    fn lens_identity() {
+        let lens = model(&[0.; 5]);
+        for p in POINTS {
+            assert!(near(lens.undistort_point(p), p, 1e-3), "{p:?}");
+            assert!(near(lens.distort_point(p), p, 1e-3), "{p:?}");
+        }
+    }
+
+    #[test]
+    fn lens_round_trip() {
+        // barrel distortion, with a slightly off-center lens
+        let lens = model(&[-0.1, 0.02, 0.001, -0.001, 0.]);
+        for p in POINTS {
+            let undistorted = lens.undistort_point(p);
+            assert!(near(lens.distort_point(undistorted), p, 0.05), "{p:?}");
+            assert!(
+                near(lens.undistort_point(lens.distort_point(p)), p, 0.05),
+                "{p:?}"
+            );
+        }
+
+        // corners move outwards once undistorted
+        let (x, y) = lens.undistort_point((50., 40.));
+        assert!(x < 45. && y < 35., "{:?}", (x, y));
+    }
+}
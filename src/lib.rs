@@ -1,76 +1,217 @@
+pub mod calibration;
 pub mod color;
+pub mod config;
+pub mod control;
+pub mod emulator;
 pub mod gui;
+pub mod kalman;
+pub mod lens;
+pub mod plant;
+pub mod safety;
 pub mod serial;
+pub mod sil;
+pub mod sink;
+pub mod source;
+pub mod synth;
+pub mod tracker;
 use std::f32::consts::PI;
+use std::time::Duration;
 
-use color::Color;
+use color::{Channels, Color, ColorRange, Hsl, Hsv};
+pub use tracker::{Localisation, Tracker};
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub struct Ball {
     pub x: f32,
     pub y: f32,
+    /// estimated from the blob size, in pixels
+    pub radius: f32,
+    /// matching pixels in the blob's bounding box
+    pub area: f32,
+    /// fraction of the blob's circle covered by matching pixels, 0 to 1
+    pub confidence: f32,
+    /// when the frame the ball was found in was captured
+    pub timestamp: Duration,
 }
 
-use cv::{
-    core::Ptr,
-    features2d::{SimpleBlobDetector, SimpleBlobDetector_Params},
-    prelude::*,
-};
+impl Ball {
+    /// Distance to the camera from the apparent size,
+    /// given the ball's real radius and the camera's focal length in pixels
+    pub fn distance(&self, real_radius: f32, focal_length: f32) -> f32 {
+        real_radius * focal_length / self.radius
+    }
+
+    fn distance_sq(&self, (x, y): (f32, f32)) -> f32 {
+        (self.x - x).powi(2) + (self.y - y).powi(2)
+    }
+}
+
+/// Ball position relative to the magnet, so it doesn't depend on
+/// where the camera is mounted
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Gap {
+    /// How far the ball's center is below the magnet, negative if above it
+    pub distance: f32,
+    /// How far the ball's center is to the right of the magnet
+    pub offset: f32,
+}
+
+impl Gap {
+    /// Both positions in image coordinates, where y grows downwards
+    pub fn new(magnet: (f32, f32), ball: (f32, f32)) -> Self {
+        Self {
+            distance: ball.1 - magnet.1,
+            offset: ball.0 - magnet.0,
+        }
+    }
+}
+
+use cv::prelude::*;
 use opencv as cv;
 
-fn lower_upper(color: Color, tolerance: u8) -> (Color, Color) {
-    (color - tolerance, color + tolerance)
+/// Which pixels count as the object.
+///
+/// The variant selects the color space the comparison happens in.
+/// The HSV / HSL ones are much less sensitive to the ball getting brighter
+/// or darker, since lighting mostly affects V / L.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Threshold {
+    Rgb(ColorRange),
+    Hsv(ColorRange<Hsv>),
+    Hsl(ColorRange<Hsl>),
 }
 
-pub fn isolate_obj(img: &Mat, color: Color, tolerance: u8, dst: &mut Mat) {
-    let (lower, upper) = lower_upper(color, tolerance);
+impl From<ColorRange> for Threshold {
+    fn from(range: ColorRange) -> Self {
+        Self::Rgb(range)
+    }
+}
 
-    let lower = cv::core::Scalar::from((lower.b().into(), lower.g().into(), lower.r().into()));
-    let upper = cv::core::Scalar::from((upper.b().into(), upper.g().into(), upper.r().into()));
+impl From<ColorRange<Hsv>> for Threshold {
+    fn from(range: ColorRange<Hsv>) -> Self {
+        Self::Hsv(range)
+    }
+}
 
-    let mut out = Mat::default();
-    cv::core::in_range(img, &lower, &upper, &mut out).unwrap();
-    cv::imgproc::cvt_color(&out, dst, cv::imgproc::COLOR_GRAY2BGR, 3).unwrap();
+impl From<ColorRange<Hsl>> for Threshold {
+    fn from(range: ColorRange<Hsl>) -> Self {
+        Self::Hsl(range)
+    }
 }
 
-fn create_blob_detector() -> Ptr<SimpleBlobDetector> {
-    let blob_params = SimpleBlobDetector_Params {
-        filter_by_color: true,
-        blob_color: 255,
-        filter_by_area: false,
-        filter_by_circularity: false,
-        filter_by_convexity: false,
-        filter_by_inertia: false,
-        ..SimpleBlobDetector_Params::default().unwrap()
-    };
+fn scalar(channels: [u8; 3]) -> cv::core::Scalar {
+    let [a, b, c] = channels.map(f64::from);
+    cv::core::Scalar::from((a, b, c))
+}
 
-    SimpleBlobDetector::create(blob_params).unwrap()
+/// Scratch images reused between calls to `threshold_mask`,
+/// so the per-frame path doesn't allocate once the frame size is stable.
+#[derive(Default)]
+pub(crate) struct MaskBuffers {
+    converted: Mat,
+    high: Mat,
+    low: Mat,
 }
 
-pub fn process_image(src: &Mat, color: Color, tolerance: u8, dst: &mut Mat) -> Option<Ball> {
-    let mut blob_detector = create_blob_detector();
+// `in_range` that also handles a first (hue) channel wrapping around,
+// where lower > upper means [lower, HUE_MAX) + [0, upper]
+fn in_range_wrapping(
+    img: &Mat,
+    lower: [u8; 3],
+    upper: [u8; 3],
+    high: &mut Mat,
+    low: &mut Mat,
+    dst: &mut Mat,
+) {
+    if lower[0] <= upper[0] {
+        cv::core::in_range(img, &scalar(lower), &scalar(upper), dst).unwrap();
+        return;
+    }
 
-    isolate_obj(src, color, tolerance, dst);
+    let high_upper = [color::HUE_MAX - 1, upper[1], upper[2]];
+    let low_lower = [0, lower[1], lower[2]];
 
-    let mut keypoints = cv::core::Vector::new();
-    blob_detector
-        .detect(dst, &mut keypoints, &cv::core::no_array())
-        .unwrap();
+    cv::core::in_range(img, &scalar(lower), &scalar(high_upper), high).unwrap();
+    cv::core::in_range(img, &scalar(low_lower), &scalar(upper), low).unwrap();
+    cv::core::bitwise_or(high, low, dst, &cv::core::no_array()).unwrap();
+}
 
-    //assert!(
-    //    keypoints.len() <= 1,
-    //    "More than 1 blob detected, maybe check color calibration"
-    //);
+/// Single channel mask of the pixels of `img` (BGR) inside `threshold`
+pub(crate) fn threshold_mask(
+    img: &Mat,
+    threshold: Threshold,
+    buffers: &mut MaskBuffers,
+    mask: &mut Mat,
+) {
+    let MaskBuffers {
+        converted,
+        high,
+        low,
+    } = buffers;
 
-    match keypoints.get(0) {
-        Ok(kp) => Some(Ball {
-            x: kp.pt.x,
-            y: kp.pt.y,
-        }),
-        Err(_) => None,
+    match threshold {
+        Threshold::Rgb(range) => {
+            let bgr = |c: Color| scalar([c.b(), c.g(), c.r()]);
+
+            cv::core::in_range(img, &bgr(range.lower), &bgr(range.upper), mask).unwrap();
+        }
+        Threshold::Hsv(range) => {
+            cv::imgproc::cvt_color(img, converted, cv::imgproc::COLOR_BGR2HSV, 0).unwrap();
+
+            in_range_wrapping(
+                converted,
+                range.lower.to_channels(),
+                range.upper.to_channels(),
+                high,
+                low,
+                mask,
+            );
+        }
+        Threshold::Hsl(range) => {
+            // OpenCV orders the channels as H, L, S
+            cv::imgproc::cvt_color(img, converted, cv::imgproc::COLOR_BGR2HLS, 0).unwrap();
+
+            let hls_order = |c: Hsl| [c.h, c.l, c.s];
+            in_range_wrapping(
+                converted,
+                hls_order(range.lower),
+                hls_order(range.upper),
+                high,
+                low,
+                mask,
+            );
+        }
     }
 }
 
+pub fn isolate_obj(img: &Mat, threshold: impl Into<Threshold>, dst: &mut Mat) {
+    let mut mask = Mat::default();
+    threshold_mask(
+        img,
+        threshold.into(),
+        &mut MaskBuffers::default(),
+        &mut mask,
+    );
+
+    cv::imgproc::cvt_color(&mask, dst, cv::imgproc::COLOR_GRAY2BGR, 3).unwrap();
+}
+
+/// Convenience for one-off images, prefer keeping a `Tracker` around for video
+/// since this sets up a new blob detector on every call.
+pub fn process_image(
+    src: &Mat,
+    threshold: impl Into<Threshold>,
+    localisation: Localisation,
+    dst: &mut Mat,
+) -> Option<Ball> {
+    let mut tracker = Tracker::new(threshold);
+    tracker.set_localisation(localisation);
+    let ball = tracker.track(src);
+
+    cv::imgproc::cvt_color(tracker.mask(), dst, cv::imgproc::COLOR_GRAY2BGR, 3).unwrap();
+    ball
+}
+
 pub fn save_img(img: &Mat, ball: Ball) {
     let size = img.size().unwrap();
     let mut out = image::RgbImage::new(size.width as u32, size.height as u32);
@@ -93,26 +234,23 @@ pub fn save_img(img: &Mat, ball: Ball) {
     out.save("out.png").unwrap();
 }
 
-//use serialport::SerialPort;
-//use std::sync::mpsc;
-//use std::thread;
-//
-//pub fn serial(port: Option<Box<dyn SerialPort>>) -> mpsc::Sender<[u8; 4]> {
-//    let (tx, rx) = mpsc::channel::<[u8; 4]>();
-//
-//    match port {
-//        Some(mut p) => thread::spawn(move || {
-//            while let Ok(bytes) = rx.recv() {
-//                p.write_all(&bytes).unwrap();
-//            }
-//        }),
-//        None => thread::spawn(move || {
-//            while let Ok(bytes) = rx.recv() {
-//                eprintln!("{bytes:?}");
-//            }
-//        }),
-//    };
-//
-//    tx
-//}
-//
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gap_sign_and_offset() {
+        let magnet = (320., 100.);
+
+        let below = Gap::new(magnet, (325., 140.));
+        assert_eq!(below.distance, 40.);
+        assert_eq!(below.offset, 5.);
+
+        // above the magnet and to its left
+        let above = Gap::new(magnet, (310., 90.));
+        assert_eq!(above.distance, -10.);
+        assert_eq!(above.offset, -10.);
+
+        assert_eq!(Gap::new(magnet, magnet), Gap::default());
+    }
+}
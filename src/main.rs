@@ -1,30 +1,176 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
 use std::sync::{
-    atomic::{AtomicU8, Ordering::SeqCst},
+    atomic::{AtomicU32, AtomicU8, Ordering::SeqCst},
     mpsc, Arc, Mutex,
 };
+use std::time::{Duration, Instant};
 
 use cv::highgui as cv_gui;
 use cv::prelude::*;
 use opencv as cv;
 
-use levitation::color::Color;
+use levitation::color::{Color, ColorRange, Hsl, Hsv};
+use levitation::config::Config;
+use levitation::control::{Pid, PidConfig};
 use levitation::gui::*;
 use levitation::isolate_obj;
-use levitation::process_image;
-use levitation::serial;
+use levitation::kalman::Kalman;
+use levitation::lens::{self, ChessboardCalibrator, LensConfig, LensModel};
+use levitation::safety::FailSafe;
+use levitation::serial::{self, Packet, Telemetry};
+use levitation::sink::{Controller, Sink, SinkKind, Stream};
+use levitation::source;
+use levitation::tracker::{Localisation, Selection};
+use levitation::{Ball, Gap, Threshold, Tracker};
 
-#[cfg(target_os = "linux")]
-const CAP_BACKEND: i32 = cv::videoio::CAP_V4L2;
-#[cfg(not(target_os = "linux"))]
-const CAP_BACKEND: i32 = cv::videoio::CAP_ANY;
+const CONFIG_PATH: &str = "levitation.conf";
+const LOG_PATH: &str = "levitation.csv";
+
+const USAGE: &str = "usage: levitation [--list-ports] [--port NAME] [--usb VID:PID]
+                  [--serial-number SERIAL] [--baud RATE] [--parity none|odd|even]
+                  [--stop-bits 1|2] [--flow-control none|software|hardware]
+
+The serial options override the [serial] section of levitation.conf
+for this run, saving the config keeps the section as it was.
+Without a port from either, it is asked for. The [sink] section can send
+to a network address or stdout instead.";
+
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    std::process::exit(1);
+}
+
+fn list_ports() {
+    match serialport::available_ports() {
+        Ok(ports) if ports.is_empty() => println!("no serial ports found"),
+        Ok(ports) => {
+            for p in &ports {
+                println!("{}", serial::describe(p));
+            }
+        }
+        Err(e) => {
+            eprintln!("failed to list serial ports: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+// a point in a frame that wasn't undistorted as a whole, in the undistorted
+// pixels the ball is measured in
+fn undistorted_point(
+    lens: &Option<LensModel>,
+    config: &LensConfig,
+    point: (f32, f32),
+) -> (f32, f32) {
+    match lens {
+        Some(l) if !config.undistort_frames => l.undistort_point(point),
+        _ => point,
+    }
+}
+
+// the inverse of `undistorted_point`
+fn distorted_point(lens: &Option<LensModel>, config: &LensConfig, point: (f32, f32)) -> (f32, f32) {
+    match lens {
+        Some(l) if !config.undistort_frames => l.distort_point(point),
+        _ => point,
+    }
+}
+
+// one row of the log, positions in mm and fields missing this frame left empty
+fn log_frame(
+    log: &mut impl Write,
+    timestamp: Duration,
+    ball: Option<Ball>,
+    control: Option<(Gap, f32)>,
+    telemetry: Option<Telemetry>,
+) -> io::Result<()> {
+    fn field(value: Option<impl ToString>) -> String {
+        value.map(|v| v.to_string()).unwrap_or_default()
+    }
+
+    writeln!(
+        log,
+        "{:.4},{},{},{},{},{},{},{},{}",
+        timestamp.as_secs_f64(),
+        field(ball.map(|b| b.x)),
+        field(ball.map(|b| b.y)),
+        field(control.map(|(gap, _)| gap.distance)),
+        field(control.map(|(_, command)| command)),
+        field(telemetry.map(|t| t.current)),
+        field(telemetry.map(|t| t.duty)),
+        field(telemetry.map(|t| t.loop_time.as_micros())),
+        field(telemetry.map(|t| t.faults.0)),
+    )
+}
 
 fn main() {
     // setup
-    let mut port = serial::select_port().unwrap();
+    let mut config = Config::load_or_default(CONFIG_PATH).expect("failed to load config");
+
+    // `--serial-number x` sets `serial_number = x` of the [serial] section,
+    // only for this run, "Save Config" keeps the one from the file
+    let mut serial_config = config.serial.clone();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--list-ports" {
+            list_ports();
+            return;
+        }
+        let key = match arg.strip_prefix("--") {
+            Some(key) => key.replace('-', "_"),
+            None => usage(),
+        };
+        let value = args.next().unwrap_or_else(|| usage());
+        if let Err(e) = serial_config.set(&key, &value) {
+            eprintln!("{arg}: {e}");
+            usage();
+        }
+    }
+
+    // reconnects by itself, frames keep being processed meanwhile.
+    // Messages go to stderr from here on, stdout may be the sink.
+    let mut sink: Box<dyn Sink> = match config.sink.kind {
+        SinkKind::Serial => {
+            let ports = serialport::available_ports().unwrap_or_default();
+            let port = match serial_config.port_id(&ports) {
+                Some(port) => port,
+                None => match serial::select_port(&ports) {
+                    Ok(port) => port,
+                    Err(e) => {
+                        eprintln!("no serial port to use: {e}");
+                        std::process::exit(1);
+                    }
+                },
+            };
+            println!("using serial port {port}");
+            Box::new(serial::Link::spawn(port, &serial_config))
+        }
+        _ => Box::new(Stream::open(&config.sink).expect("failed to open output")),
+    };
     cv_gui::named_window(WINDOW_NAME, cv_gui::WINDOW_NORMAL).expect("failed to create window");
 
-    let mut cap = cv::videoio::VideoCapture::new(0, CAP_BACKEND).expect("failed to open camera");
+    let mut source = source::open(&config.source).expect("failed to open frame source");
     let mut cam_frame = cv::core::Mat::default();
+    let mut was_grabbed = true;
+    let mut timestamp = Duration::ZERO;
+    let mut undistorted = Mat::default();
+    let mut mask = Mat::default();
+
+    let mut lens = match LensModel::load(&config.lens.file) {
+        Ok(l) => {
+            eprintln!("loaded lens calibration, error {:.3} px", l.rms);
+            Some(l)
+        }
+        // not calibrated yet
+        Err(_) if !Path::new(&config.lens.file).exists() => None,
+        Err(e) => {
+            eprintln!("failed to load lens calibration: {e}");
+            None
+        }
+    };
+    let mut chessboard = ChessboardCalibrator::new(&config.lens);
 
     let (tx, rx) = mpsc::channel();
     let tx = Arc::new(Mutex::new(tx));
@@ -33,8 +179,37 @@ fn main() {
 
     levitation::gui::create_buttons(tx.clone());
 
-    let tolerance = Arc::new(AtomicU8::new(0));
-    levitation::gui::create_tolerance_trackbar(tolerance.clone());
+    let rgb_tolerance = Arc::new(Mutex::new(Color::black()));
+    levitation::gui::create_tolerance_trackbars_rgb(rgb_tolerance.clone());
+
+    let hsv_tolerance = Arc::new(Mutex::new(Hsv::default()));
+    levitation::gui::create_tolerance_trackbars(hsv_tolerance.clone());
+
+    let color_space = Arc::new(AtomicU8::new(0));
+    levitation::gui::create_color_space_trackbar(color_space.clone());
+
+    let selection = Selection::ALL
+        .iter()
+        .position(|&s| s == config.tracker.selection)
+        .unwrap();
+    let selection = Arc::new(AtomicU8::new(selection as u8));
+    levitation::gui::create_selection_trackbar(selection.clone());
+
+    let localisation = Localisation::ALL
+        .iter()
+        .position(|&l| l == config.tracker.localisation)
+        .unwrap();
+    let localisation = Arc::new(AtomicU8::new(localisation as u8));
+    levitation::gui::create_localisation_trackbar(localisation.clone());
+
+    let blob_params = Arc::new(Mutex::new(config.blob));
+    levitation::gui::create_blob_trackbars(blob_params.clone());
+
+    let pid_config = Arc::new(Mutex::new(config.pid));
+    levitation::gui::create_pid_trackbars(pid_config.clone());
+
+    let reference_mm = Arc::new(AtomicU32::new(50));
+    levitation::gui::create_reference_trackbar(reference_mm.clone());
 
     // object uses detection by color,
     // while the magnet just uses the position,
@@ -45,20 +220,63 @@ fn main() {
     let mut select_magnet = false;
     let mut is_raw = true;
 
+    // clicks collected for the calibration in progress
+    let mut calibration_clicks = Vec::new();
+    let mut calibration_step = None;
+
     let mut ball = None;
+    let mut kalman = Kalman::new(config.kalman);
+    let mut pid = Pid::new(config.pid);
+    let mut fail_safe = FailSafe::new(config.safety);
 
-    let mut obj_frame = Mat::default();
+    let mut tracker =
+        Tracker::with_params(ColorRange::new(Color::black(), Color::black()), config.blob);
+    tracker.set_config(&config.tracker);
 
-    port.set_timeout(std::time::Duration::from_millis(500))
-        .unwrap();
+    let mut last_telemetry = None;
+    let mut was_connected = false;
+    // what a remote controller was last told
+    let mut sent_pid: Option<PidConfig> = None;
 
-    //let port = levitation::select_port().unwrap();
+    let mut log = BufWriter::new(File::create(LOG_PATH).expect("failed to create log"));
+    writeln!(
+        log,
+        "time,ball_x,ball_y,gap,command,current,duty,loop_us,faults"
+    )
+    .unwrap();
 
     loop {
         // get camera frame
-        if !cap.read(&mut cam_frame).unwrap() {
+        let grabbed = match source.read(&mut cam_frame) {
+            Some(t) => {
+                timestamp = t;
+                true
+            }
+            None if source.is_finished() => {
+                eprintln!("end of {}", config.source.path);
+                break;
+            }
+            None => false,
+        };
+        if !grabbed && was_grabbed {
             eprintln!("NO FRAMES GRABBED");
         }
+        was_grabbed = grabbed;
+
+        // what's tracked and shown, `cam_frame` stays as captured
+        let frame = match (&mut lens, config.lens.undistort_frames) {
+            (Some(l), true) => {
+                if grabbed {
+                    l.undistort_frame(&cam_frame, &mut undistorted);
+                }
+                &undistorted
+            }
+            _ => &cam_frame,
+        };
+
+        let mut control = None;
+        // the ball's gap if it was detected in this frame
+        let mut seen = None;
 
         // listen for message from UI elements
         if let Ok(msg) = rx.try_recv() {
@@ -66,20 +284,82 @@ fn main() {
                 Message::SelectObject => {
                     select_object = true;
                     select_magnet = false;
+                    calibration_step = None;
                 }
                 Message::SelectMagnet => {
                     select_magnet = true;
                     select_object = false;
+                    calibration_step = None;
                 }
+                Message::CalibrateScale | Message::CalibrateTilt => {
+                    select_magnet = false;
+                    select_object = false;
+                    calibration_step = Some(msg);
+                    calibration_clicks.clear();
+                }
+                Message::CaptureChessboard => {
+                    // the lens is calibrated from what it actually sees
+                    if chessboard.capture(&cam_frame) {
+                        eprintln!("captured chessboard view {}", chessboard.views());
+                    } else {
+                        eprintln!("no chessboard found");
+                    }
+                }
+                Message::CalibrateLens => match chessboard.calibrate() {
+                    Some(l) => {
+                        eprintln!("lens calibrated, error {:.3} px", l.rms);
+                        if let Err(e) = l.save(&config.lens.file) {
+                            eprintln!("failed to save lens calibration: {e}");
+                        }
+                        lens = Some(l);
+                    }
+                    None => eprintln!(
+                        "need at least {} chessboard views, have {}",
+                        lens::MIN_VIEWS,
+                        chessboard.views()
+                    ),
+                },
+                Message::CalibrateDiameter => match ball {
+                    Some(b) => {
+                        let mm = reference_mm.load(SeqCst) as f32;
+                        if !config.calibration.calibrate_diameter(&b, mm) {
+                            eprintln!("invalid calibration, try again");
+                        }
+                    }
+                    None => eprintln!("no ball detected to calibrate against"),
+                },
 
                 // mouse pointer position
                 Message::Position(x, y) => {
                     // convert MAT element to Color
-                    let col: cv::core::Vec3b = *cam_frame.at_2d(y, x).unwrap();
+                    let col: cv::core::Vec3b = *frame.at_2d(y, x).unwrap();
                     let col = Color::new(col.0[2], col.0[1], col.0[0]);
+                    let point = undistorted_point(&lens, &config.lens, (x as f32, y as f32));
+
+                    if let Some(step) = calibration_step {
+                        calibration_clicks.push(point);
 
-                    if select_magnet {
+                        let cal = &mut config.calibration;
+                        let done = match (step, calibration_clicks.as_slice()) {
+                            (Message::CalibrateScale, &[a, b]) => {
+                                Some(cal.calibrate_points(a, b, reference_mm.load(SeqCst) as f32))
+                            }
+                            (Message::CalibrateTilt, &[a, b, c, d]) => {
+                                Some(cal.calibrate_tilt([a, b, c, d]))
+                            }
+                            _ => None,
+                        };
+                        if let Some(valid) = done {
+                            if !valid {
+                                eprintln!("invalid calibration, try again");
+                            } else if matches!(step, Message::CalibrateTilt) {
+                                eprintln!("tilt corrected, calibrate the scale again");
+                            }
+                            calibration_step = None;
+                        }
+                    } else if select_magnet {
                         magnet_pos = Some((x, y));
+                        config.calibration.set_origin(point);
                         select_magnet = false;
                     } else if select_object {
                         object_color = Some(col);
@@ -89,31 +369,160 @@ fn main() {
                 Message::ToggleRaw(b) => is_raw = b,
                 Message::SaveImg => {
                     if let Some(b) = ball {
-                        levitation::save_img(&cam_frame, b);
+                        levitation::save_img(frame, b);
+                    }
+                }
+                Message::SaveConfig => {
+                    config.tracker.selection = Selection::ALL[selection.load(SeqCst) as usize];
+                    config.tracker.localisation =
+                        Localisation::ALL[localisation.load(SeqCst) as usize];
+                    config.blob = *blob_params.lock().unwrap();
+                    config.pid = *pid_config.lock().unwrap();
+                    if let Err(e) = config.save(CONFIG_PATH) {
+                        eprintln!("failed to save config: {e}");
                     }
                 }
             }
         }
 
-        if let Some(col) = object_color {
+        if !grabbed {
+            // nothing new to track or show
+        } else if let Some(col) = object_color {
             if is_raw {
-                cv_gui::imshow(WINDOW_NAME, &cam_frame).unwrap();
+                cv_gui::imshow(WINDOW_NAME, frame).unwrap();
             } else {
-                let tol = tolerance.load(SeqCst);
+                let hsv_tol = *hsv_tolerance.lock().unwrap();
+                let threshold: Threshold = match color_space.load(SeqCst) {
+                    0 => ColorRange::from_tolerance(col, *rgb_tolerance.lock().unwrap()).into(),
+                    1 => ColorRange::from_tolerance(col.to_hsv(), hsv_tol).into(),
+                    _ => ColorRange::from_tolerance(
+                        col.to_hsl(),
+                        Hsl::new(hsv_tol.h, hsv_tol.s, hsv_tol.v),
+                    )
+                    .into(),
+                };
 
-                //isolate_obj(&cam_frame, col, tol, &mut obj_frame);
-                ball = process_image(&cam_frame, col, tol, &mut obj_frame);
-                if let Some(b) = &ball {
-                    //println!("{b:?}");
-                    serial::send_data(&mut *port, b.y);
+                tracker.set_threshold(threshold);
+                tracker.set_params(*blob_params.lock().unwrap());
+                tracker.set_selection(Selection::ALL[selection.load(SeqCst) as usize]);
+                tracker.set_localisation(Localisation::ALL[localisation.load(SeqCst) as usize]);
+                tracker.set_magnet(magnet_pos.map(|(x, y)| (x as f32, y as f32)));
+                // the filter works in undistorted pixels, the tracker in the frame's
+                tracker.set_prediction(
+                    kalman
+                        .position_at(timestamp)
+                        .map(|p| distorted_point(&lens, &config.lens, p)),
+                );
+                ball = tracker.track_at(frame, timestamp);
+                if let (Some(l), false) = (&lens, config.lens.undistort_frames) {
+                    ball = ball.map(|b| l.undistort_ball(&b));
                 }
-                cv_gui::imshow(WINDOW_NAME, &obj_frame).unwrap();
+                let state = match &ball {
+                    Some(b) => Some(kalman.update(b)),
+                    None => kalman.predict(timestamp),
+                };
+                // positions are only meaningful relative to the magnet
+                pid.set_config(*pid_config.lock().unwrap());
+                if let (Some(s), Some((mx, my))) = (&state, magnet_pos) {
+                    let cal = &config.calibration;
+                    let s = cal.state_to_mm(s);
+                    let magnet = undistorted_point(&lens, &config.lens, (mx as f32, my as f32));
+                    let gap = Gap::new(cal.to_mm(magnet), (s.x, s.y));
+                    let command = pid.update(gap.distance, s.timestamp);
+                    control = Some((gap, command));
+                    if ball.is_some() {
+                        seen = Some(gap);
+                    }
+                }
+                // the same size as the frame, so clicks land where they would on it
+                tracker.frame_mask(&mut mask);
+                cv_gui::imshow(WINDOW_NAME, &mask).unwrap();
             }
         } else {
-            cv_gui::imshow(WINDOW_NAME, &cam_frame).unwrap();
+            cv_gui::imshow(WINDOW_NAME, frame).unwrap();
         }
 
-        let key = cv_gui::poll_key().unwrap();
+        let connected = sink.is_connected();
+        if connected && !was_connected {
+            // it may have been reset meanwhile
+            sink.send(Packet::Enable(fail_safe.is_safe()));
+            sent_pid = None;
+        }
+        was_connected = connected;
+
+        // the coil only runs while the ball is seen where it should be,
+        // also when the camera stops delivering frames
+        let now = Instant::now();
+        if let Some(packet) = fail_safe.update(now, seen) {
+            match fail_safe.trip() {
+                Some(trip) => eprintln!("coil off: {trip}"),
+                None => eprintln!("coil on"),
+            }
+            sink.send(packet);
+        }
+        match config.sink.controller {
+            Controller::Host => match control {
+                Some((_, command)) if fail_safe.is_safe() => sink.send(Packet::Command(command)),
+                _ => pid.reset(),
+            },
+            Controller::Remote => {
+                let c = *pid_config.lock().unwrap();
+                if sent_pid != Some(c) {
+                    sink.send(Packet::Setpoint(c.setpoint));
+                    sink.send(Packet::Gains {
+                        kp: c.kp,
+                        ki: c.ki,
+                        kd: c.kd,
+                    });
+                    sent_pid = Some(c);
+                }
+                if let Some(gap) = seen {
+                    sink.send(Packet::Position(gap));
+                }
+            }
+        }
+        if let Some(heartbeat) = fail_safe.heartbeat(now) {
+            sink.send(heartbeat);
+        }
+
+        for e in sink.errors() {
+            eprintln!("write failed: {e}");
+        }
+        if let Some(t) = sink.telemetry().pop() {
+            last_telemetry = Some(t);
+        }
+        if !connected {
+            last_telemetry = None;
+        }
+        let stats = sink.stats();
+        let status = format!(
+            "{} | {} | {} | write {:.1} ms, max {:.1} ms, {} dropped, {} failed",
+            sink.state(),
+            fail_safe
+                .trip()
+                .map_or("coil on".to_string(), |t| format!("coil off: {t}")),
+            last_telemetry.map_or("no telemetry".to_string(), |t| t.to_string()),
+            stats.mean_latency().as_secs_f64() * 1000.,
+            stats.max_latency.as_secs_f64() * 1000.,
+            stats.dropped,
+            stats.errors,
+        );
+        cv_gui::display_status_bar(WINDOW_NAME, &status, 0).unwrap();
+        // one row per frame
+        if grabbed {
+            let ball_mm = ball.map(|b| config.calibration.ball_to_mm(&b));
+            if let Err(e) = log_frame(&mut log, timestamp, ball_mm, control, last_telemetry) {
+                eprintln!("failed to write log: {e}");
+            }
+        }
+
+        // dropping the sink on the way out turns the coil off
+        let key = if grabbed {
+            cv_gui::poll_key().unwrap()
+        } else {
+            // a camera that fails returns straight away, don't spin on it
+            cv_gui::wait_key(10).unwrap()
+        };
         if key == 27 {
             break;
         }
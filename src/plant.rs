@@ -0,0 +1,382 @@
+//! Simulation of the rig: the ball hanging under the electromagnet,
+//! driven by the same command the serial link carries, and seen through
+//! a sensor with latency and noise.
+//!
+//! The API is in mm and seconds like the rest of the control loop,
+//! the integration happens in SI units.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+// longest step of the integration, the coil's time constant is a few ms
+const MAX_STEP: f64 = 1e-4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlantConfig {
+    /// kg
+    pub mass: f64,
+    /// m/s²
+    pub gravity: f64,
+    /// Magnetic force is `force_constant * i² / (gap + force_offset)²`, in N·m²/A²
+    pub force_constant: f64,
+    /// Distance from the magnet's face to the point the field seems
+    /// to come from, in mm
+    pub force_offset: f64,
+    /// Coil inductance, H
+    pub inductance: f64,
+    /// Coil resistance, Ω
+    pub resistance: f64,
+    /// Voltage across the coil with a command of 1, V
+    pub supply: f64,
+    /// Gap at which the ball rests on the floor, in mm
+    pub floor: f64,
+    /// How old measurements are when they become available, in seconds
+    pub latency: f64,
+    /// Standard deviation of the measured gap, in mm
+    pub noise: f64,
+    pub seed: u64,
+}
+
+impl Default for PlantConfig {
+    fn default() -> Self {
+        // a ~17 mm steel ball held at 15 mm by about 1 A
+        Self {
+            mass: 0.02,
+            gravity: 9.81,
+            force_constant: 5.9e-4,
+            force_offset: 40.,
+            inductance: 0.02,
+            resistance: 10.,
+            supply: 24.,
+            floor: 40.,
+            latency: 0.01,
+            noise: 0.05,
+            seed: 0,
+        }
+    }
+}
+
+crate::section!(
+    PlantConfig,
+    "plant",
+    [
+        mass,
+        gravity,
+        force_constant,
+        force_offset,
+        inductance,
+        resistance,
+        supply,
+        floor,
+        latency,
+        noise,
+        seed,
+    ]
+);
+
+/// A measurement of the gap, in mm, taken at `timestamp`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Sample {
+    pub gap: f32,
+    pub timestamp: Duration,
+}
+
+// xorshift64*, good enough for sensor noise and keeps runs reproducible
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // the state must not be 0
+        Self(seed ^ 0x9e37_79b9_7f4a_7c15)
+    }
+
+    // uniform in (0, 1]
+    fn uniform(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let bits = self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11;
+        (bits + 1) as f64 / (1u64 << 53) as f64
+    }
+
+    // standard normal, Box-Muller
+    fn normal(&mut self) -> f64 {
+        let (u, v) = (self.uniform(), self.uniform());
+        (-2. * u.ln()).sqrt() * (2. * std::f64::consts::PI * v).cos()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Plant {
+    config: PlantConfig,
+    /// m below the magnet
+    gap: f64,
+    /// m/s, positive when falling away from the magnet
+    velocity: f64,
+    /// A
+    current: f64,
+    command: f32,
+    time: Duration,
+    /// true gaps not yet old enough to be measured
+    pending: VecDeque<Sample>,
+    rng: Rng,
+}
+
+impl Plant {
+    /// Starts at rest with the coil off, `gap` mm below the magnet
+    pub fn new(config: PlantConfig, gap: f32) -> Self {
+        let mut plant = Self {
+            config,
+            gap: gap as f64 / 1000.,
+            velocity: 0.,
+            current: 0.,
+            command: 0.,
+            time: Duration::ZERO,
+            pending: VecDeque::new(),
+            rng: Rng::new(config.seed),
+        };
+        plant.record();
+        plant
+    }
+
+    pub fn config(&self) -> PlantConfig {
+        self.config
+    }
+
+    /// Starts in equilibrium at `gap` mm: the current and command are the
+    /// ones holding the ball still there. Measurements from before are dropped.
+    pub fn hold(&mut self, gap: f32) {
+        self.gap = gap as f64 / 1000.;
+        self.velocity = 0.;
+        self.command = self.equilibrium_command(gap);
+        self.current = self.command as f64 * self.config.supply / self.config.resistance;
+
+        self.pending.clear();
+        self.record();
+    }
+
+    /// What the serial link carries, 0 (coil off) to 1 (full supply voltage).
+    /// Kept until the next call.
+    pub fn set_command(&mut self, command: f32) {
+        self.command = command.clamp(0., 1.);
+    }
+
+    pub fn command(&self) -> f32 {
+        self.command
+    }
+
+    /// True gap, in mm
+    pub fn gap(&self) -> f32 {
+        (self.gap * 1000.) as f32
+    }
+
+    /// mm/s, positive when falling away from the magnet
+    pub fn velocity(&self) -> f32 {
+        (self.velocity * 1000.) as f32
+    }
+
+    /// Coil current, A
+    pub fn current(&self) -> f32 {
+        self.current as f32
+    }
+
+    pub fn time(&self) -> Duration {
+        self.time
+    }
+
+    /// Magnetic force on the ball, in N, with `current` A at a gap of `gap` mm
+    pub fn force(&self, current: f64, gap: f64) -> f64 {
+        let distance = (gap + self.config.force_offset) / 1000.;
+        self.config.force_constant * current * current / (distance * distance)
+    }
+
+    /// Steady command that exactly cancels gravity at `gap` mm
+    pub fn equilibrium_command(&self, gap: f32) -> f32 {
+        let c = &self.config;
+        let distance = (gap as f64 + c.force_offset) / 1000.;
+        let current = distance * (c.mass * c.gravity / c.force_constant).sqrt();
+        (current * c.resistance / c.supply) as f32
+    }
+
+    /// Advances the simulation by `dt`
+    pub fn step(&mut self, dt: Duration) {
+        let c = self.config;
+        let steps = (dt.as_secs_f64() / MAX_STEP).ceil().max(1.);
+        let h = dt.as_secs_f64() / steps;
+        let voltage = self.command as f64 * c.supply;
+
+        for _ in 0..steps as usize {
+            // L di/dt = V - R i, and the driver can't push the current negative
+            self.current += (voltage - c.resistance * self.current) / c.inductance * h;
+            self.current = self.current.max(0.);
+
+            let force = self.force(self.current, self.gap * 1000.);
+            let acceleration = c.gravity - force / c.mass;
+
+            // semi-implicit Euler
+            self.velocity += acceleration * h;
+            self.gap += self.velocity * h;
+
+            // stopped by the magnet's face or the floor
+            let floor = c.floor / 1000.;
+            if self.gap < 0. {
+                self.gap = 0.;
+                self.velocity = self.velocity.max(0.);
+            } else if self.gap > floor {
+                self.gap = floor;
+                self.velocity = self.velocity.min(0.);
+            }
+        }
+
+        self.time += dt;
+        self.record();
+    }
+
+    fn record(&mut self) {
+        self.pending.push_back(Sample {
+            gap: self.gap(),
+            timestamp: self.time,
+        });
+    }
+
+    /// Newest measurement that's at least `latency` old, with noise added.
+    /// `None` until the first one is, or if it was already returned.
+    pub fn measure(&mut self) -> Option<Sample> {
+        let available = self
+            .time
+            .checked_sub(Duration::from_secs_f64(self.config.latency))?;
+
+        let mut newest = None;
+        while let Some(sample) = self.pending.front().filter(|s| s.timestamp <= available) {
+            newest = Some(*sample);
+            self.pending.pop_front();
+        }
+
+        newest.map(|s| Sample {
+            gap: s.gap + (self.rng.normal() * self.config.noise) as f32,
+            ..s
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{Pid, PidConfig};
+
+    fn secs(t: f64) -> Duration {
+        Duration::from_secs_f64(t)
+    }
+
+    #[test]
+    fn plant_dynamics() {
+        // free fall
+        let mut plant = Plant::new(PlantConfig::default(), 10.);
+        plant.step(secs(0.05));
+        let fallen = 0.5 * 9.81 * 0.05 * 0.05 * 1000.;
+        assert!((plant.gap() - 10. - fallen as f32).abs() < 0.1, "{plant:?}");
+
+        // and lands on the floor
+        plant.step(secs(1.));
+        assert_eq!(plant.gap(), 40.);
+        assert_eq!(plant.velocity(), 0.);
+
+        // inverse square
+        let f = plant.force(1., 15.);
+        assert!((plant.force(2., 15.) / f - 4.).abs() < 1e-9);
+        let offset = plant.config().force_offset;
+        let ratio = ((15. + offset) / (45. + offset)).powi(2);
+        assert!((plant.force(1., 45.) / f - ratio).abs() < 1e-9);
+
+        // equilibrium is unstable, but holds for a moment
+        plant.hold(15.);
+        assert!((plant.force(plant.current() as f64, 15.) - 0.02 * 9.81).abs() < 1e-4);
+        plant.step(secs(0.02));
+        assert!((plant.gap() - 15.).abs() < 0.01, "{plant:?}");
+
+        // more current pulls the ball up
+        plant.set_command(1.);
+        plant.step(secs(0.02));
+        assert!(plant.gap() < 15., "{plant:?}");
+    }
+
+    #[test]
+    fn plant_sensor() {
+        let config = PlantConfig {
+            latency: 0.02,
+            noise: 0.,
+            ..PlantConfig::default()
+        };
+        let mut plant = Plant::new(config, 10.);
+
+        plant.step(secs(0.01));
+        assert_eq!(plant.measure(), None);
+
+        plant.step(secs(0.01));
+        assert_eq!(
+            plant.measure(),
+            Some(Sample {
+                gap: 10.,
+                timestamp: Duration::ZERO
+            })
+        );
+        assert_eq!(plant.measure(), None);
+
+        let gap = plant.gap();
+        plant.step(secs(0.02));
+        let sample = plant.measure().unwrap();
+        assert_eq!(sample.gap, gap);
+        assert_eq!(sample.timestamp, secs(0.02));
+
+        // nothing from before a hold is measured after it
+        plant.step(secs(0.01));
+        plant.hold(25.);
+        plant.step(secs(0.02));
+        assert!((plant.measure().unwrap().gap - 25.).abs() < 1e-4);
+        assert_eq!(plant.measure(), None);
+
+        // noise is reproducible and about the right size
+        let config = PlantConfig {
+            noise: 0.1,
+            ..config
+        };
+        let noisy = |seed| {
+            let mut plant = Plant::new(PlantConfig { seed, ..config }, 10.);
+            (0..1000)
+                .map(|_| {
+                    plant.hold(10.);
+                    plant.step(secs(0.02));
+                    plant.measure().unwrap().gap - 10.
+                })
+                .collect::<Vec<_>>()
+        };
+        let a = noisy(1);
+        assert_eq!(a, noisy(1));
+        assert_ne!(a, noisy(2));
+
+        let variance = a.iter().map(|e| e * e).sum::<f32>() / a.len() as f32;
+        assert!((variance.sqrt() - 0.1).abs() < 0.01, "{variance}");
+    }
+
+    #[test]
+    fn plant_pid_hold() {
+        let mut plant = Plant::new(PlantConfig::default(), 20.);
+        plant.hold(20.);
+
+        let mut pid = Pid::new(PidConfig {
+            bias: plant.equilibrium_command(15.),
+            ..PidConfig::default()
+        });
+
+        let dt = secs(1. / 200.);
+        for _ in 0..400 {
+            plant.step(dt);
+            if let Some(sample) = plant.measure() {
+                plant.set_command(pid.update(sample.gap, sample.timestamp));
+            }
+        }
+        assert!((plant.gap() - 15.).abs() < 0.5, "{plant:?}");
+        assert!(plant.velocity().abs() < 10., "{plant:?}");
+    }
+}
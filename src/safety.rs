@@ -0,0 +1,246 @@
+//! Keeps the coil from acting on stale commands: the host proves it's alive
+//! with heartbeats, and turns the coil off itself when the ball is lost or
+//! somewhere it shouldn't be.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::serial::Packet;
+use crate::Gap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SafetyConfig {
+    /// Seconds between heartbeats, well under the firmware's watchdog timeout
+    pub heartbeat_interval: f64,
+    /// How long the ball may go undetected before the coil is turned off,
+    /// in seconds
+    pub lost_timeout: f64,
+    /// Range of gaps the ball may be held at, in mm
+    pub min_gap: f32,
+    pub max_gap: f32,
+    /// How far the ball may swing sideways, in mm
+    pub max_offset: f32,
+}
+
+impl Default for SafetyConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: 0.1,
+            lost_timeout: 0.25,
+            min_gap: 2.,
+            max_gap: 35.,
+            max_offset: 10.,
+        }
+    }
+}
+
+crate::section!(
+    SafetyConfig,
+    "safety",
+    [
+        heartbeat_interval,
+        lost_timeout,
+        min_gap,
+        max_gap,
+        max_offset,
+    ]
+);
+
+impl SafetyConfig {
+    pub fn contains(&self, gap: Gap) -> bool {
+        (self.min_gap..=self.max_gap).contains(&gap.distance) && gap.offset.abs() <= self.max_offset
+    }
+}
+
+/// Why the coil is off
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trip {
+    /// Not detected for longer than `lost_timeout`, or not yet
+    Lost,
+    /// Last seen outside the safe envelope
+    Envelope(Gap),
+}
+
+impl fmt::Display for Trip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Lost => f.write_str("ball lost"),
+            Self::Envelope(gap) => write!(
+                f,
+                "ball out of bounds at {:.1} mm, {:.1} mm off centre",
+                gap.distance, gap.offset
+            ),
+        }
+    }
+}
+
+/// Decides when the coil may be driven and what to tell the microcontroller.
+///
+/// The coil is allowed on again as soon as the ball is seen inside the
+/// envelope, e.g. held there by hand after it dropped to the floor.
+#[derive(Debug, Clone)]
+pub struct FailSafe {
+    config: SafetyConfig,
+    last_seen: Option<Instant>,
+    trip: Option<Trip>,
+    last_heartbeat: Option<Instant>,
+    sequence: u32,
+}
+
+impl FailSafe {
+    /// Starts tripped, nothing has been seen yet
+    pub fn new(config: SafetyConfig) -> Self {
+        Self {
+            config,
+            last_seen: None,
+            trip: Some(Trip::Lost),
+            last_heartbeat: None,
+            sequence: 0,
+        }
+    }
+
+    pub fn config(&self) -> SafetyConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: SafetyConfig) {
+        self.config = config;
+    }
+
+    pub fn trip(&self) -> Option<Trip> {
+        self.trip
+    }
+
+    pub fn is_safe(&self) -> bool {
+        self.trip.is_none()
+    }
+
+    /// Checks the ball's gap if it was detected at `now`, `None` if it wasn't
+    /// or no frame came in. Returns the `Enable` packet to send if that
+    /// changes whether the coil may be on.
+    ///
+    /// Timed by the wall clock rather than frame timestamps, which stop
+    /// along with the camera.
+    pub fn update(&mut self, now: Instant, gap: Option<Gap>) -> Option<Packet> {
+        let lost_timeout = Duration::from_secs_f64(self.config.lost_timeout);
+
+        let trip = match gap {
+            Some(gap) => {
+                self.last_seen = Some(now);
+                (!self.config.contains(gap)).then_some(Trip::Envelope(gap))
+            }
+            // missing the odd frame is fine
+            None => match self.last_seen {
+                Some(seen) if now.saturating_duration_since(seen) <= lost_timeout => self.trip,
+                _ => Some(Trip::Lost),
+            },
+        };
+
+        let changed = trip.is_some() != self.trip.is_some();
+        self.trip = trip;
+        changed.then_some(Packet::Enable(trip.is_none()))
+    }
+
+    /// A heartbeat, if one is due at `now`
+    pub fn heartbeat(&mut self, now: Instant) -> Option<Packet> {
+        let interval = Duration::from_secs_f64(self.config.heartbeat_interval);
+        if self
+            .last_heartbeat
+            .is_some_and(|last| now.saturating_duration_since(last) < interval)
+        {
+            return None;
+        }
+
+        self.last_heartbeat = Some(now);
+        self.sequence = self.sequence.wrapping_add(1);
+        Some(Packet::Heartbeat(self.sequence))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(t: u64) -> Duration {
+        Duration::from_millis(t)
+    }
+
+    fn gap(distance: f32) -> Option<Gap> {
+        Some(Gap {
+            distance,
+            offset: 0.,
+        })
+    }
+
+    #[test]
+    fn safety_lost() {
+        let mut safety = FailSafe::new(SafetyConfig::default());
+        let start = Instant::now();
+        let at = |t| start + ms(t);
+        assert_eq!(safety.trip(), Some(Trip::Lost));
+        assert_eq!(safety.update(at(0), None), None);
+
+        assert_eq!(safety.update(at(10), gap(15.)), Some(Packet::Enable(true)));
+        assert_eq!(safety.update(at(20), gap(15.)), None);
+
+        // a few missed frames are tolerated
+        assert_eq!(safety.update(at(200), None), None);
+        assert!(safety.is_safe());
+        assert_eq!(safety.update(at(300), None), Some(Packet::Enable(false)));
+        assert_eq!(safety.trip(), Some(Trip::Lost));
+        assert_eq!(safety.update(at(400), None), None);
+
+        assert_eq!(safety.update(at(500), gap(15.)), Some(Packet::Enable(true)));
+    }
+
+    #[test]
+    fn safety_envelope() {
+        let mut safety = FailSafe::new(SafetyConfig::default());
+        let start = Instant::now();
+        let at = |t| start + ms(t);
+        safety.update(at(0), gap(15.));
+
+        assert_eq!(safety.update(at(10), gap(36.)), Some(Packet::Enable(false)));
+        assert!(matches!(safety.trip(), Some(Trip::Envelope(_))));
+        // stays off while it isn't seen either
+        assert_eq!(safety.update(at(20), None), None);
+        assert_eq!(safety.update(at(400), None), None);
+        assert_eq!(safety.trip(), Some(Trip::Lost));
+
+        assert_eq!(safety.update(at(410), gap(1.)), None);
+        let sideways = Some(Gap {
+            distance: 15.,
+            offset: -11.,
+        });
+        assert_eq!(safety.update(at(420), sideways), None);
+        assert_eq!(safety.update(at(430), gap(35.)), Some(Packet::Enable(true)));
+    }
+
+    #[test]
+    fn safety_source_stopped() {
+        let mut safety = FailSafe::new(SafetyConfig::default());
+        let start = Instant::now();
+        let at = |t| start + ms(t);
+        assert_eq!(safety.update(at(0), gap(15.)), Some(Packet::Enable(true)));
+
+        // no frames, the loop keeps going with nothing seen
+        assert_eq!(safety.update(at(100), None), None);
+        assert_eq!(safety.update(at(200), None), None);
+        assert_eq!(safety.update(at(300), None), Some(Packet::Enable(false)));
+        assert_eq!(safety.trip(), Some(Trip::Lost));
+    }
+
+    #[test]
+    fn safety_heartbeat() {
+        let mut safety = FailSafe::new(SafetyConfig::default());
+        let start = Instant::now();
+
+        assert_eq!(safety.heartbeat(start), Some(Packet::Heartbeat(1)));
+        assert_eq!(safety.heartbeat(start + ms(50)), None);
+        assert_eq!(
+            safety.heartbeat(start + ms(100)),
+            Some(Packet::Heartbeat(2))
+        );
+        assert_eq!(safety.heartbeat(start + ms(150)), None);
+    }
+}
@@ -1,16 +1,1147 @@
-use serialport::SerialPort;
-use std::io::prelude::*;
+//! Link to the microcontroller.
+//!
+//! Every message is a frame:
+//!
+//! | bytes | field                                       |
+//! |-------|---------------------------------------------|
+//! | 2     | sync, `0xAA 0x55`                           |
+//! | 1     | protocol version                            |
+//! | 1     | message type                                |
+//! | 1     | payload length                              |
+//! | n     | payload, big-endian                         |
+//! | 2     | CRC-16/CCITT-FALSE of version to payload    |
+//!
+//! so a receiver that lost a byte finds the next sync header and carries on.
 
-pub fn send_data(port: &mut dyn SerialPort, data: f32) {
-    port.clear(serialport::ClearBuffer::All).unwrap();
-    port.write_all(&data.to_be_bytes()).unwrap();
+use serialport::{SerialPort, SerialPortInfo, SerialPortType};
+use std::collections::VecDeque;
+use std::io::{self, prelude::*};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use std::{fmt, thread};
+
+use crate::Gap;
+
+pub const SYNC: [u8; 2] = [0xAA, 0x55];
+pub const VERSION: u8 = 1;
+pub const MAX_PAYLOAD: usize = 32;
+
+// sync, version, type, length
+const HEADER_LEN: usize = 5;
+const CRC_LEN: usize = 2;
+const MAX_FRAME: usize = HEADER_LEN + MAX_PAYLOAD + CRC_LEN;
+
+/// CRC-16/CCITT-FALSE, polynomial 0x1021 starting from 0xFFFF
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xFFFF_u16;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Packet {
+    /// Ball's position relative to the magnet, in mm
+    Position(Gap),
+    /// Gap the firmware's controller should hold, in mm
+    Setpoint(f32),
+    Gains {
+        kp: f32,
+        ki: f32,
+        kd: f32,
+    },
+    /// Coil output from the host's controller, 0 to 1
+    Command(f32),
+    /// Turns the coil driver on or off
+    Enable(bool),
+    /// Sent periodically with an incrementing number. The firmware turns
+    /// the coil off if these stop coming.
+    Heartbeat(u32),
+    /// Sent by the microcontroller
+    Telemetry(Telemetry),
+}
+
+/// Fault flags reported by the microcontroller
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Faults(pub u16);
+
+impl Faults {
+    pub const OVERCURRENT: Self = Self(1 << 0);
+    pub const OVERTEMPERATURE: Self = Self(1 << 1);
+    pub const UNDERVOLTAGE: Self = Self(1 << 2);
+    /// The microcontroller missed its own loop deadline
+    pub const OVERRUN: Self = Self(1 << 3);
+    /// Heartbeats stopped, the coil was turned off
+    pub const WATCHDOG: Self = Self(1 << 4);
+
+    const NAMES: [(Self, &'static str); 5] = [
+        (Self::OVERCURRENT, "overcurrent"),
+        (Self::OVERTEMPERATURE, "overtemperature"),
+        (Self::UNDERVOLTAGE, "undervoltage"),
+        (Self::OVERRUN, "overrun"),
+        (Self::WATCHDOG, "watchdog"),
+    ];
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl fmt::Display for Faults {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("none");
+        }
+
+        let mut parts: Vec<String> = Self::NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| name.to_string())
+            .collect();
+
+        let unknown = Self::NAMES
+            .iter()
+            .fold(self.0, |bits, (flag, _)| bits & !flag.0);
+        if unknown != 0 {
+            parts.push(format!("{unknown:#06x}"));
+        }
+        f.write_str(&parts.join(" "))
+    }
+}
+
+/// What the microcontroller reports about itself
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Telemetry {
+    /// Coil current, A
+    pub current: f32,
+    /// PWM duty cycle, 0 to 1
+    pub duty: f32,
+    /// How long its last control loop iteration took, sent in µs
+    pub loop_time: Duration,
+    pub faults: Faults,
+}
+
+impl fmt::Display for Telemetry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "coil {:.2} A, duty {:.0}%, loop {} us, faults: {}",
+            self.current,
+            self.duty * 100.,
+            self.loop_time.as_micros(),
+            self.faults
+        )
+    }
+}
+
+mod kind {
+    pub const POSITION: u8 = 0x01;
+    pub const SETPOINT: u8 = 0x02;
+    pub const GAINS: u8 = 0x03;
+    pub const COMMAND: u8 = 0x04;
+    pub const ENABLE: u8 = 0x05;
+    pub const HEARTBEAT: u8 = 0x06;
+    // from the microcontroller
+    pub const TELEMETRY: u8 = 0x81;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    Version(u8),
+    /// Declared payload length over `MAX_PAYLOAD`
+    Length(u8),
+    Crc {
+        expected: u16,
+        actual: u16,
+    },
+    UnknownType(u8),
+    /// Payload length doesn't match the message type
+    Payload {
+        kind: u8,
+        len: usize,
+    },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Version(v) => write!(f, "unsupported protocol version {v}"),
+            Self::Length(len) => write!(f, "payload of {len} bytes is too long"),
+            Self::Crc { expected, actual } => {
+                write!(f, "bad checksum {actual:#06x}, expected {expected:#06x}")
+            }
+            Self::UnknownType(kind) => write!(f, "unknown message type {kind:#04x}"),
+            Self::Payload { kind, len } => {
+                write!(f, "wrong payload length {len} for message type {kind:#04x}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+fn f32s<const N: usize>(payload: &[u8]) -> [f32; N] {
+    let mut values = [0.; N];
+    for (v, bytes) in values.iter_mut().zip(payload.chunks_exact(4)) {
+        *v = f32::from_be_bytes(bytes.try_into().unwrap());
+    }
+    values
+}
+
+impl Packet {
+    pub fn kind(&self) -> u8 {
+        match self {
+            Self::Position(_) => kind::POSITION,
+            Self::Setpoint(_) => kind::SETPOINT,
+            Self::Gains { .. } => kind::GAINS,
+            Self::Command(_) => kind::COMMAND,
+            Self::Enable(_) => kind::ENABLE,
+            Self::Heartbeat(_) => kind::HEARTBEAT,
+            Self::Telemetry(_) => kind::TELEMETRY,
+        }
+    }
+
+    fn write_payload(&self, out: &mut Vec<u8>) {
+        let mut put = |values: &[f32]| {
+            for v in values {
+                out.extend_from_slice(&v.to_be_bytes());
+            }
+        };
+        match *self {
+            Self::Position(gap) => put(&[gap.distance, gap.offset]),
+            Self::Setpoint(v) | Self::Command(v) => put(&[v]),
+            Self::Gains { kp, ki, kd } => put(&[kp, ki, kd]),
+            Self::Enable(on) => out.push(on as u8),
+            Self::Heartbeat(sequence) => out.extend_from_slice(&sequence.to_be_bytes()),
+            Self::Telemetry(t) => {
+                put(&[t.current, t.duty]);
+                let micros = t.loop_time.as_micros().min(u32::MAX as u128) as u32;
+                out.extend_from_slice(&micros.to_be_bytes());
+                out.extend_from_slice(&t.faults.0.to_be_bytes());
+            }
+        }
+    }
+
+    fn parse(kind: u8, payload: &[u8]) -> Result<Self, DecodeError> {
+        let expected = match kind {
+            kind::POSITION => 8,
+            kind::SETPOINT | kind::COMMAND | kind::HEARTBEAT => 4,
+            kind::GAINS => 12,
+            kind::ENABLE => 1,
+            kind::TELEMETRY => 14,
+            _ => return Err(DecodeError::UnknownType(kind)),
+        };
+        if payload.len() != expected {
+            return Err(DecodeError::Payload {
+                kind,
+                len: payload.len(),
+            });
+        }
+
+        Ok(match kind {
+            kind::POSITION => {
+                let [distance, offset] = f32s(payload);
+                Self::Position(Gap { distance, offset })
+            }
+            kind::SETPOINT => Self::Setpoint(f32s::<1>(payload)[0]),
+            kind::COMMAND => Self::Command(f32s::<1>(payload)[0]),
+            kind::GAINS => {
+                let [kp, ki, kd] = f32s(payload);
+                Self::Gains { kp, ki, kd }
+            }
+            kind::ENABLE => Self::Enable(payload[0] != 0),
+            kind::HEARTBEAT => Self::Heartbeat(u32::from_be_bytes(payload.try_into().unwrap())),
+            _ => {
+                let [current, duty] = f32s(payload);
+                let micros = u32::from_be_bytes(payload[8..12].try_into().unwrap());
+                Self::Telemetry(Telemetry {
+                    current,
+                    duty,
+                    loop_time: Duration::from_micros(micros as u64),
+                    faults: Faults(u16::from_be_bytes([payload[12], payload[13]])),
+                })
+            }
+        })
+    }
+
+    /// The whole frame, ready to be written
+    pub fn encode(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(MAX_FRAME);
+        frame.extend_from_slice(&SYNC);
+        frame.extend_from_slice(&[VERSION, self.kind(), 0]);
+        self.write_payload(&mut frame);
+        frame[4] = (frame.len() - HEADER_LEN) as u8;
+
+        let crc = crc16(&frame[SYNC.len()..]);
+        frame.extend_from_slice(&crc.to_be_bytes());
+        frame
+    }
+}
+
+/// Reassembles packets from a byte stream, skipping anything that isn't
+/// a valid frame. Doesn't allocate, so it ports to firmware as is.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    buf: [u8; MAX_FRAME],
+    len: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self {
+            buf: [0; MAX_FRAME],
+            len: 0,
+        }
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.buf.copy_within(n..self.len, 0);
+        self.len -= n;
+    }
+
+    /// Adds one received byte, returning the packet or error it completes.
+    ///
+    /// After an error the rest of the bad frame is searched for the next
+    /// sync header, so drain the decoder as an iterator before pushing
+    /// more if packets must not be delayed.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Packet, DecodeError>> {
+        // can't overflow, a frame is handled as soon as it's complete
+        self.buf[self.len] = byte;
+        self.len += 1;
+        self.next()
+    }
+
+    /// Every packet and error in `bytes`
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<Result<Packet, DecodeError>> {
+        let mut out = Vec::new();
+        for &byte in bytes {
+            if let Some(res) = self.push(byte) {
+                out.push(res);
+                out.extend(self.by_ref());
+            }
+        }
+        out
+    }
+}
+
+/// Packets and errors from the bytes already pushed
+impl Iterator for Decoder {
+    type Item = Result<Packet, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // skip to the next sync header, keeping a possible partial one
+        let start = (0..self.len)
+            .find(|&i| {
+                let rest = &self.buf[i..self.len];
+                (rest.len() < SYNC.len() && rest[0] == SYNC[0]) || rest.starts_with(&SYNC)
+            })
+            .unwrap_or(self.len);
+        self.consume(start);
+
+        if self.len < HEADER_LEN {
+            return None;
+        }
+
+        let [version, kind, payload_len] = [self.buf[2], self.buf[3], self.buf[4]];
+        let error = if version != VERSION {
+            Some(DecodeError::Version(version))
+        } else if payload_len as usize > MAX_PAYLOAD {
+            Some(DecodeError::Length(payload_len))
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            self.consume(1);
+            return Some(Err(error));
+        }
+
+        let frame_len = HEADER_LEN + payload_len as usize + CRC_LEN;
+        if self.len < frame_len {
+            return None;
+        }
+
+        let body = &self.buf[SYNC.len()..frame_len - CRC_LEN];
+        let expected = crc16(body);
+        let actual = u16::from_be_bytes([self.buf[frame_len - 2], self.buf[frame_len - 1]]);
+        if expected != actual {
+            // this wasn't a frame after all, a real one may start inside it
+            self.consume(1);
+            return Some(Err(DecodeError::Crc { expected, actual }));
+        }
+
+        let packet = Packet::parse(kind, &self.buf[HEADER_LEN..frame_len - CRC_LEN]);
+        self.consume(frame_len);
+        Some(packet)
+    }
 }
 
-pub fn select_port() -> serialport::Result<Box<dyn SerialPort>> {
-    let ports = serialport::available_ports().expect("Could not get any serial ports");
+/// Blocks until written, see `Writer` for the capture loop
+pub fn send<W: Write + ?Sized>(port: &mut W, packet: &Packet) -> io::Result<()> {
+    port.write_all(&packet.encode())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WriteStats {
+    pub written: u64,
+    /// Replaced by a newer packet of the same type, or cleared, before
+    /// being written
+    pub dropped: u64,
+    pub errors: u64,
+    /// From `Writer::send` to the write completing
+    pub last_latency: Duration,
+    pub max_latency: Duration,
+    pub total_latency: Duration,
+}
+
+impl WriteStats {
+    pub fn mean_latency(&self) -> Duration {
+        match self.written {
+            0 => Duration::ZERO,
+            n => self.total_latency / n as u32,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    /// at most one packet of each type, oldest first
+    pending: VecDeque<(Packet, Instant)>,
+    closed: bool,
+}
+
+/// Writes packets on a background thread, so a stalled port never holds up
+/// the capture loop.
+///
+/// Only the newest packet of each type waits to be written: a command that
+/// wasn't sent before the next one is stale anyway. Write errors are
+/// reported through `errors` rather than stopping the thread.
+pub struct Writer {
+    queue: Arc<(Mutex<Queue>, Condvar)>,
+    stats: Arc<Mutex<WriteStats>>,
+    errors: mpsc::Receiver<io::Error>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Writer {
+    pub fn spawn(port: impl Write + Send + 'static) -> Self {
+        Self::with_encoder(port, Packet::encode)
+    }
+
+    /// Like `spawn`, writing packets as `encode` turns them into bytes,
+    /// in a single `write_all` each
+    pub fn with_encoder(
+        mut port: impl Write + Send + 'static,
+        encode: impl Fn(&Packet) -> Vec<u8> + Send + 'static,
+    ) -> Self {
+        let queue = Arc::new((Mutex::new(Queue::default()), Condvar::new()));
+        let stats = Arc::new(Mutex::new(WriteStats::default()));
+        let (tx, errors) = mpsc::channel();
+
+        let thread = {
+            let queue = queue.clone();
+            let stats = stats.clone();
+            thread::spawn(move || loop {
+                let (packet, queued) = {
+                    let (lock, ready) = &*queue;
+                    let mut q = ready
+                        .wait_while(lock.lock().unwrap(), |q| q.pending.is_empty() && !q.closed)
+                        .unwrap();
+                    match q.pending.pop_front() {
+                        Some(next) => next,
+                        None => return,
+                    }
+                };
+
+                let res = port.write_all(&encode(&packet));
+
+                let mut stats = stats.lock().unwrap();
+                match res {
+                    Ok(()) => {
+                        let latency = queued.elapsed();
+                        stats.written += 1;
+                        stats.last_latency = latency;
+                        stats.max_latency = stats.max_latency.max(latency);
+                        stats.total_latency += latency;
+                    }
+                    Err(e) => {
+                        stats.errors += 1;
+                        // nobody listening is fine, the count is still kept
+                        let _ = tx.send(e);
+                    }
+                }
+            })
+        };
+
+        Self {
+            queue,
+            stats,
+            errors,
+            thread: Some(thread),
+        }
+    }
+
+    /// Queues `packet` without blocking, replacing any packet of the same
+    /// type still waiting
+    pub fn send(&self, packet: Packet) {
+        let (lock, ready) = &*self.queue;
+        let mut q = lock.lock().unwrap();
+
+        if let Some(i) = q
+            .pending
+            .iter()
+            .position(|(p, _)| p.kind() == packet.kind())
+        {
+            q.pending.remove(i);
+            self.stats.lock().unwrap().dropped += 1;
+        }
+        q.pending.push_back((packet, Instant::now()));
+        ready.notify_one();
+    }
+
+    /// Drops every packet still waiting, a packet being written is finished
+    pub fn clear(&self) {
+        self.clearer()();
+    }
+
+    // `clear` for threads that don't own the `Writer`
+    fn clearer(&self) -> impl Fn() + Send + 'static {
+        let queue = self.queue.clone();
+        let stats = self.stats.clone();
+        move || {
+            let cleared = std::mem::take(&mut queue.0.lock().unwrap().pending).len();
+            stats.lock().unwrap().dropped += cleared as u64;
+        }
+    }
+
+    pub fn stats(&self) -> WriteStats {
+        *self.stats.lock().unwrap()
+    }
+
+    /// Write errors since the last call
+    pub fn errors(&self) -> mpsc::TryIter<'_, io::Error> {
+        self.errors.try_iter()
+    }
+}
+
+impl Drop for Writer {
+    /// Writes what's still queued, then stops the thread
+    fn drop(&mut self) {
+        let (lock, ready) = &*self.queue;
+        lock.lock().unwrap().closed = true;
+        ready.notify_one();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// short, so the link notices being stopped quickly
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+// some drivers keep an unplugged adapter's port readable, timing out forever
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Parity {
+    #[default]
+    None,
+    Odd,
+    Even,
+}
+
+crate::keywords!(Parity, [None => "none", Odd => "odd", Even => "even"]);
+
+impl From<Parity> for serialport::Parity {
+    fn from(p: Parity) -> Self {
+        match p {
+            Parity::None => Self::None,
+            Parity::Odd => Self::Odd,
+            Parity::Even => Self::Even,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StopBits {
+    #[default]
+    One,
+    Two,
+}
+
+crate::keywords!(StopBits, [One => "1", Two => "2"]);
+
+impl From<StopBits> for serialport::StopBits {
+    fn from(s: StopBits) -> Self {
+        match s {
+            StopBits::One => Self::One,
+            StopBits::Two => Self::Two,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlowControl {
+    #[default]
+    None,
+    /// XON/XOFF
+    Software,
+    /// RTS/CTS
+    Hardware,
+}
+
+crate::keywords!(
+    FlowControl,
+    [None => "none", Software => "software", Hardware => "hardware"]
+);
+
+impl From<FlowControl> for serialport::FlowControl {
+    fn from(f: FlowControl) -> Self {
+        match f {
+            FlowControl::None => Self::None,
+            FlowControl::Software => Self::Software,
+            FlowControl::Hardware => Self::Hardware,
+        }
+    }
+}
+
+/// A USB adapter's `vid:pid` in hex, the way `lsusb` shows it, or empty
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UsbMatch(pub Option<(u16, u16)>);
+
+impl FromStr for UsbMatch {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Ok(Self(None));
+        }
+        let (vid, pid) = s.split_once(':').ok_or(())?;
+        let hex = |s| u16::from_str_radix(s, 16).map_err(|_| ());
+        Ok(Self(Some((hex(vid)?, hex(pid)?))))
+    }
+}
+
+impl fmt::Display for UsbMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some((vid, pid)) => write!(f, "{vid:04x}:{pid:04x}"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerialConfig {
+    /// Like `/dev/ttyACM0` or `COM3`. With neither this nor `usb` set the
+    /// port is asked for on startup.
+    pub port: String,
+    /// Picks the adapter by `vid:pid` instead, whatever it's called.
+    /// Wins over `port`, which then only breaks ties.
+    pub usb: UsbMatch,
+    /// Tells apart adapters with the same `usb`, empty matches any
+    pub serial_number: String,
+    pub baud: u32,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self {
+            port: String::new(),
+            usb: UsbMatch::default(),
+            serial_number: String::new(),
+            baud: 115_200,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+        }
+    }
+}
+
+crate::section!(
+    SerialConfig,
+    "serial",
+    [
+        port,
+        usb,
+        serial_number,
+        baud,
+        parity,
+        stop_bits,
+        flow_control,
+    ]
+);
+
+impl SerialConfig {
+    /// The configured port among `ports`, `None` if none is configured.
+    ///
+    /// A configured adapter that isn't plugged in yet still gives an id,
+    /// so the `Link` picks it up once it is.
+    pub fn port_id(&self, ports: &[SerialPortInfo]) -> Option<PortId> {
+        let Some((vid, pid)) = self.usb.0 else {
+            if self.port.is_empty() {
+                return None;
+            }
+            // knowing it's USB lets it be found again under another name
+            return Some(
+                ports
+                    .iter()
+                    .find(|p| p.port_name == self.port)
+                    .map_or_else(|| PortId::name(&self.port), PortId::from),
+            );
+        };
+
+        let serial_number = (!self.serial_number.is_empty()).then(|| self.serial_number.clone());
+        let found: Vec<_> = ports
+            .iter()
+            .filter(|p| match &p.port_type {
+                SerialPortType::UsbPort(usb) => {
+                    usb.vid == vid
+                        && usb.pid == pid
+                        && (serial_number.is_none() || usb.serial_number == serial_number)
+                }
+                _ => false,
+            })
+            .collect();
+        let found = found
+            .iter()
+            .find(|p| p.port_name == self.port)
+            .or(found.first());
+
+        Some(match found {
+            Some(p) => PortId::from(*p),
+            None => PortId {
+                name: self.port.clone(),
+                usb: Some(UsbId {
+                    vid,
+                    pid,
+                    serial_number,
+                }),
+            },
+        })
+    }
+
+    fn builder(&self, name: &str) -> serialport::SerialPortBuilder {
+        serialport::new(name, self.baud)
+            .parity(self.parity.into())
+            .stop_bits(self.stop_bits.into())
+            .flow_control(self.flow_control.into())
+            .timeout(READ_TIMEOUT)
+    }
+}
+
+/// What identifies a USB serial adapter whatever name it's given
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbId {
+    pub vid: u16,
+    pub pid: u16,
+    pub serial_number: Option<String>,
+}
+
+/// The port to reconnect to. A USB adapter that resets can come back under
+/// another name, so it's found again by its `UsbId`, other ports by name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortId {
+    pub name: String,
+    pub usb: Option<UsbId>,
+}
+
+impl From<&SerialPortInfo> for PortId {
+    fn from(info: &SerialPortInfo) -> Self {
+        let usb = match &info.port_type {
+            SerialPortType::UsbPort(usb) => Some(UsbId {
+                vid: usb.vid,
+                pid: usb.pid,
+                serial_number: usb.serial_number.clone(),
+            }),
+            _ => None,
+        };
+        Self {
+            name: info.port_name.clone(),
+            usb,
+        }
+    }
+}
+
+impl PortId {
+    /// Just a name, e.g. a pseudo terminal that isn't listed by
+    /// `serialport::available_ports`
+    pub fn name(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            usb: None,
+        }
+    }
+
+    pub fn matches(&self, info: &SerialPortInfo) -> bool {
+        match (&self.usb, &info.port_type) {
+            (Some(id), SerialPortType::UsbPort(usb)) => {
+                id.vid == usb.vid && id.pid == usb.pid && id.serial_number == usb.serial_number
+            }
+            (Some(_), _) => false,
+            (None, _) => info.port_name == self.name,
+        }
+    }
+
+    /// This port among `ports`. Identical adapters without a serial number
+    /// can't be told apart, then the one with the same name wins.
+    pub fn find<'a>(&self, ports: &'a [SerialPortInfo]) -> Option<&'a SerialPortInfo> {
+        let mut matching = ports.iter().filter(|p| self.matches(p));
+        let first = matching.next()?;
+        Some(
+            std::iter::once(first)
+                .chain(matching)
+                .find(|p| p.port_name == self.name)
+                .unwrap_or(first),
+        )
+    }
+
+    // where the port is now
+    fn locate(&self) -> serialport::Result<String> {
+        if self.usb.is_none() {
+            return Ok(self.name.clone());
+        }
+        let ports = serialport::available_ports()?;
+        match self.find(&ports) {
+            Some(p) => Ok(p.port_name.clone()),
+            None => Err(serialport::Error::new(
+                serialport::ErrorKind::NoDevice,
+                format!("{self} is not plugged in"),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for PortId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+        if let Some(usb) = &self.usb {
+            write!(f, " ({:04x}:{:04x}", usb.vid, usb.pid)?;
+            if let Some(serial) = &usb.serial_number {
+                write!(f, " {serial}")?;
+            }
+            f.write_str(")")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkState {
+    /// On the port with this name
+    Connected(String),
+    /// Trying to open the port again every `RETRY_INTERVAL`
+    Disconnected { since: Instant, attempts: u32 },
+}
+
+impl fmt::Display for LinkState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connected(name) => write!(f, "serial: connected on {name}"),
+            Self::Disconnected { since, attempts } => write!(
+                f,
+                "serial: disconnected for {} s, {attempts} attempts to reconnect",
+                since.elapsed().as_secs()
+            ),
+        }
+    }
+}
+
+// locked on its own while writing, so a stalled write doesn't hold up
+// swapping the port
+type SharedPort = Arc<Mutex<Box<dyn SerialPort>>>;
+
+struct Shared {
+    state: Mutex<LinkState>,
+    /// written to by the `Writer`, `None` while disconnected
+    port: Mutex<Option<SharedPort>>,
+    stop: AtomicBool,
+}
+
+impl Shared {
+    fn set_state(&self, state: LinkState) {
+        *self.state.lock().unwrap() = state;
+    }
+}
+
+// timing out is how a quiet port looks, anything else means it's gone
+fn is_lost(e: &io::Error) -> bool {
+    !matches!(
+        e.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+    )
+}
+
+// what the `Writer` writes to
+struct LinkPort(Arc<Shared>);
+
+impl LinkPort {
+    fn port(&self) -> io::Result<SharedPort> {
+        let port = self.0.port.lock().unwrap().clone();
+        port.ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+}
+
+impl Write for LinkPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let port = self.port()?;
+        let res = port.lock().unwrap().write(buf);
+        if res.as_ref().is_err_and(is_lost) {
+            // tells the reading side to reconnect, unless it already has
+            let mut current = self.0.port.lock().unwrap();
+            if current.as_ref().is_some_and(|p| Arc::ptr_eq(p, &port)) {
+                *current = None;
+            }
+        }
+        res
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let port = self.port()?;
+        let mut port = port.lock().unwrap();
+        port.flush()
+    }
+}
+
+/// The serial link to the microcontroller, surviving it being unplugged
+/// or reset.
+///
+/// A background thread opens the port, decodes what's received and, once
+/// the port fails or disappears, keeps trying to open it again. Packets are
+/// written by a `Writer`, those still queued when the port is lost are
+/// dropped. Nothing here blocks or panics, so the capture
+/// loop carries on without the link.
+pub struct Link {
+    // declared first so it's dropped first, flushing to the port
+    writer: Writer,
+    telemetry: mpsc::Receiver<Telemetry>,
+    shared: Arc<Shared>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Link {
+    /// Starts connecting to `port` with the settings in `config`,
+    /// the first attempt may fail too
+    pub fn spawn(port: PortId, config: &SerialConfig) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(LinkState::Disconnected {
+                since: Instant::now(),
+                attempts: 0,
+            }),
+            port: Mutex::new(None),
+            stop: AtomicBool::new(false),
+        });
+        let (tx, telemetry) = mpsc::channel();
+        let writer = Writer::spawn(LinkPort(shared.clone()));
+
+        let thread = {
+            let shared = shared.clone();
+            let config = config.clone();
+            let clear = writer.clearer();
+            thread::spawn(move || supervise(&shared, &port, &config, &tx, &clear))
+        };
+
+        Self {
+            writer,
+            telemetry,
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn state(&self) -> LinkState {
+        self.shared.state.lock().unwrap().clone()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        matches!(self.state(), LinkState::Connected(_))
+    }
+
+    /// Queues `packet`, see `Writer::send`. Discarded while disconnected,
+    /// it would be stale by the time the port is back.
+    pub fn send(&self, packet: Packet) {
+        if self.is_connected() {
+            self.writer.send(packet);
+        }
+    }
+
+    /// Telemetry received since the last call
+    pub fn telemetry(&self) -> mpsc::TryIter<'_, Telemetry> {
+        self.telemetry.try_iter()
+    }
+
+    pub fn stats(&self) -> WriteStats {
+        self.writer.stats()
+    }
+
+    /// Write errors since the last call
+    pub fn errors(&self) -> mpsc::TryIter<'_, io::Error> {
+        self.writer.errors()
+    }
+}
+
+impl Drop for Link {
+    /// Turns the coil off, also when unwinding from a panic, and stops
+    /// reconnecting. The port stays open until the `Writer` is done.
+    fn drop(&mut self) {
+        self.send(Packet::Enable(false));
+        self.shared.stop.store(true, SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// `clear` drops the packets queued for the previous connection
+fn supervise(
+    shared: &Shared,
+    id: &PortId,
+    config: &SerialConfig,
+    tx: &mpsc::Sender<Telemetry>,
+    clear: &dyn Fn(),
+) {
+    let mut attempts = 0;
+    let mut since = Instant::now();
+
+    while !shared.stop.load(SeqCst) {
+        let opened = id.locate().and_then(|name| {
+            let port = config.builder(&name).open()?;
+            let reader = port.try_clone()?;
+            Ok((name, port, reader))
+        });
+        let (name, port, reader) = match opened {
+            Ok(opened) => opened,
+            Err(_) => {
+                attempts += 1;
+                shared.set_state(LinkState::Disconnected { since, attempts });
+                thread::sleep(RETRY_INTERVAL);
+                continue;
+            }
+        };
+
+        // anything queued while the state still said connected is stale too
+        clear();
+        *shared.port.lock().unwrap() = Some(Arc::new(Mutex::new(port)));
+        shared.set_state(LinkState::Connected(name.clone()));
+        if attempts > 0 {
+            eprintln!("serial: reconnected on {name}");
+        }
+
+        match read(shared, id, reader, tx) {
+            // stopped, the port is left to the writer
+            Ok(()) => return,
+            Err(e) => eprintln!("serial: lost {name}: {e}"),
+        }
+
+        attempts = 0;
+        since = Instant::now();
+        shared.set_state(LinkState::Disconnected { since, attempts });
+        *shared.port.lock().unwrap() = None;
+        clear();
+    }
+}
+
+/// Decodes telemetry until stopped or the port is lost
+fn read(
+    shared: &Shared,
+    id: &PortId,
+    mut port: Box<dyn SerialPort>,
+    tx: &mpsc::Sender<Telemetry>,
+) -> io::Result<()> {
+    let mut decoder = Decoder::new();
+    let mut buf = [0; 256];
+    let mut present = Instant::now();
+
+    while !shared.stop.load(SeqCst) {
+        if shared.port.lock().unwrap().is_none() {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "write failed"));
+        }
+
+        let n = match port.read(&mut buf) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => n,
+            Err(e) if is_lost(&e) => return Err(e),
+            Err(_) => 0,
+        };
+
+        for res in decoder.feed(&buf[..n]) {
+            match res {
+                // the receiver only goes away with the link
+                Ok(Packet::Telemetry(t)) => {
+                    let _ = tx.send(t);
+                }
+                Ok(packet) => {
+                    eprintln!("unexpected packet from the microcontroller: {packet:?}")
+                }
+                Err(e) => eprintln!("serial: {e}"),
+            }
+        }
+
+        if id.usb.is_some() && present.elapsed() >= PRESENCE_INTERVAL {
+            id.locate()?;
+            present = Instant::now();
+        }
+    }
+    Ok(())
+}
+
+/// One line about `port` for `--list-ports`
+pub fn describe(port: &SerialPortInfo) -> String {
+    match &port.port_type {
+        SerialPortType::UsbPort(usb) => {
+            let mut line = format!("{}  usb {:04x}:{:04x}", port.port_name, usb.vid, usb.pid);
+            for (label, value) in [
+                ("manufacturer", &usb.manufacturer),
+                ("product", &usb.product),
+                ("serial number", &usb.serial_number),
+            ] {
+                if let Some(value) = value {
+                    line += &format!("  {label}: {value}");
+                }
+            }
+            line
+        }
+        SerialPortType::PciPort => format!("{}  pci", port.port_name),
+        SerialPortType::BluetoothPort => format!("{}  bluetooth", port.port_name),
+        SerialPortType::Unknown => port.port_name.clone(),
+    }
+}
+
+/// Asks on stdin which of `ports` to use
+pub fn select_port(ports: &[SerialPortInfo]) -> serialport::Result<PortId> {
+    if ports.is_empty() {
+        return Err(serialport::Error::new(
+            serialport::ErrorKind::NoDevice,
+            "no serial ports found",
+        ));
+    }
+
     println!("Available ports:");
     for (i, p) in ports.iter().enumerate() {
-        println!("[{}]: {}", i, p.port_name);
+        println!("[{}]: {}", i, describe(p));
     }
 
     let mut buf = String::new();
@@ -18,11 +1149,17 @@ pub fn select_port() -> serialport::Result<Box<dyn SerialPort>> {
     let stdin = std::io::stdin();
     let mut stdout = std::io::stdout();
 
-    let port_info = loop {
+    loop {
         buf.clear();
         print!("Select a port: ");
         stdout.flush().unwrap();
-        stdin.read_line(&mut buf).unwrap();
+        // end of input, e.g. run without a terminal, leaves nobody to ask
+        if stdin.read_line(&mut buf)? == 0 {
+            return Err(serialport::Error::new(
+                serialport::ErrorKind::NoDevice,
+                "no serial port selected",
+            ));
+        }
 
         let port_index: usize = match buf.trim().parse() {
             Ok(i) => i,
@@ -33,28 +1170,384 @@ pub fn select_port() -> serialport::Result<Box<dyn SerialPort>> {
         };
 
         match ports.get(port_index) {
-            Some(i) => break i,
+            Some(p) => break Ok(PortId::from(p)),
             None => {
                 eprintln!("Please enter a valid port index");
                 continue;
             }
         }
-    };
+    }
+}
 
-    loop {
-        buf.clear();
-        print!("Enter baud rate: ");
-        stdout.flush().unwrap();
-        stdin.read_line(&mut buf).unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let baud: u32 = match buf.trim().parse() {
-            Ok(i) => i,
-            Err(_) => {
-                eprintln!("Please enter a valid baud rate");
-                continue;
+    fn packets() -> Vec<Packet> {
+        vec![
+            Packet::Position(Gap {
+                distance: 14.5,
+                offset: -0.25,
+            }),
+            Packet::Setpoint(15.),
+            Packet::Gains {
+                kp: 0.015,
+                ki: 0.1,
+                kd: 0.0007,
+            },
+            Packet::Command(0.42),
+            Packet::Enable(true),
+            Packet::Enable(false),
+            Packet::Heartbeat(0xDEAD_BEEF),
+            Packet::Telemetry(Telemetry {
+                current: 1.25,
+                duty: 0.5,
+                loop_time: Duration::from_micros(250),
+                faults: Faults(Faults::OVERCURRENT.0 | Faults::OVERRUN.0),
+            }),
+        ]
+    }
+
+    #[test]
+    fn serial_round_trip() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+
+        let frame = Packet::Setpoint(1.).encode();
+        assert_eq!(
+            frame[..HEADER_LEN],
+            [0xAA, 0x55, VERSION, kind::SETPOINT, 4]
+        );
+        assert_eq!(frame.len(), HEADER_LEN + 4 + CRC_LEN);
+
+        let stream: Vec<u8> = packets().iter().flat_map(Packet::encode).collect();
+        let decoded: Vec<_> = Decoder::new().feed(&stream);
+        let expected: Vec<_> = packets().into_iter().map(Ok).collect();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn serial_resync() {
+        let packets = packets();
+        let mut stream = vec![0x00, 0xAA, 0x13, 0x55];
+        for p in &packets {
+            stream.extend(p.encode());
+        }
+
+        // a dropped byte, and a flipped bit
+        let first = packets[0].encode().len() + 4;
+        stream.remove(first + 6);
+        stream[first + 20] ^= 0x10;
+
+        let decoded = Decoder::new().feed(&stream);
+        let ok: Vec<Packet> = decoded.iter().filter_map(|r| r.ok()).collect();
+        let mut expected = packets.clone();
+        expected.drain(1..3);
+        assert_eq!(ok, expected);
+        assert!(decoded
+            .iter()
+            .any(|r| matches!(r, Err(DecodeError::Crc { .. }))));
+    }
+
+    #[test]
+    fn serial_faults() {
+        assert_eq!(Faults::default().to_string(), "none");
+        let faults = Faults(Faults::OVERCURRENT.0 | Faults::UNDERVOLTAGE.0);
+        assert!(faults.contains(Faults::UNDERVOLTAGE));
+        assert!(!faults.contains(Faults::OVERRUN));
+        assert_eq!(faults.to_string(), "overcurrent undervoltage");
+        assert_eq!(Faults(0x8001).to_string(), "overcurrent 0x8000");
+        assert_eq!(Faults(0x8000).to_string(), "0x8000");
+        assert_eq!(Faults::WATCHDOG.to_string(), "watchdog");
+    }
+
+    // blocks every write until the test lets it through
+    struct Gated {
+        entered: mpsc::Sender<()>,
+        gate: mpsc::Receiver<io::Result<()>>,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Write for Gated {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.entered.send(()).unwrap();
+            self.gate.recv().unwrap()?;
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn serial_writer() {
+        let (entered_tx, entered) = mpsc::channel();
+        let (gate, gate_rx) = mpsc::channel();
+        let written = Arc::new(Mutex::new(Vec::new()));
+
+        let writer = Writer::spawn(Gated {
+            entered: entered_tx,
+            gate: gate_rx,
+            written: written.clone(),
+        });
+
+        // the port stalls on the first packet, meanwhile only the newest
+        // command stays queued
+        writer.send(Packet::Command(0.1));
+        entered.recv().unwrap();
+        writer.send(Packet::Command(0.2));
+        writer.send(Packet::Enable(true));
+        writer.send(Packet::Command(0.3));
+        assert_eq!(writer.stats().dropped, 1);
+
+        gate.send(Err(io::ErrorKind::BrokenPipe.into())).unwrap();
+        entered.recv().unwrap();
+        gate.send(Ok(())).unwrap();
+        entered.recv().unwrap();
+        gate.send(Ok(())).unwrap();
+
+        drop(writer);
+        let decoded = Decoder::new().feed(&written.lock().unwrap());
+        assert_eq!(
+            decoded,
+            [Ok(Packet::Enable(true)), Ok(Packet::Command(0.3))]
+        );
+    }
+
+    #[test]
+    fn serial_writer_clear() {
+        let (entered_tx, entered) = mpsc::channel();
+        let (gate, gate_rx) = mpsc::channel();
+        let written = Arc::new(Mutex::new(Vec::new()));
+
+        let writer = Writer::spawn(Gated {
+            entered: entered_tx,
+            gate: gate_rx,
+            written: written.clone(),
+        });
+
+        // the packet being written goes out, the queued ones don't
+        writer.send(Packet::Command(0.1));
+        entered.recv().unwrap();
+        writer.send(Packet::Command(0.2));
+        writer.send(Packet::Enable(true));
+        writer.clear();
+        assert_eq!(writer.stats().dropped, 2);
+        gate.send(Ok(())).unwrap();
+
+        drop(writer);
+        let decoded = Decoder::new().feed(&written.lock().unwrap());
+        assert_eq!(decoded, [Ok(Packet::Command(0.1))]);
+    }
+
+    #[test]
+    fn serial_writer_stats() {
+        let (entered_tx, entered) = mpsc::channel();
+        let (gate, gate_rx) = mpsc::channel();
+
+        let writer = Writer::spawn(Gated {
+            entered: entered_tx,
+            gate: gate_rx,
+            written: Default::default(),
+        });
+
+        writer.send(Packet::Command(0.1));
+        entered.recv().unwrap();
+        gate.send(Err(io::ErrorKind::TimedOut.into())).unwrap();
+
+        writer.send(Packet::Command(0.2));
+        entered.recv().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        gate.send(Ok(())).unwrap();
+
+        while writer.stats().written == 0 {
+            thread::yield_now();
+        }
+        let stats = writer.stats();
+        assert_eq!(stats.errors, 1);
+        assert!(stats.last_latency >= Duration::from_millis(20), "{stats:?}");
+        assert_eq!(stats.max_latency, stats.last_latency);
+        assert_eq!(stats.mean_latency(), stats.last_latency);
+
+        let errors: Vec<_> = writer.errors().map(|e| e.kind()).collect();
+        assert_eq!(errors, [io::ErrorKind::TimedOut]);
+    }
+
+    fn usb(name: &str, pid: u16, serial_number: Option<&str>) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: name.into(),
+            port_type: SerialPortType::UsbPort(serialport::UsbPortInfo {
+                vid: 0x0483,
+                pid,
+                serial_number: serial_number.map(Into::into),
+                manufacturer: None,
+                product: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn serial_port_id() {
+        let id = PortId::from(&usb("/dev/ttyACM0", 0x5740, Some("A1")));
+        assert_eq!(id.to_string(), "/dev/ttyACM0 (0483:5740 A1)");
+
+        // came back under another name, next to a different adapter
+        let ports = [
+            usb("/dev/ttyACM0", 0x5740, Some("B2")),
+            usb("/dev/ttyACM1", 0x5740, Some("A1")),
+        ];
+        assert_eq!(id.find(&ports).unwrap().port_name, "/dev/ttyACM1");
+        assert_eq!(id.find(&ports[..1]), None);
+
+        // a port that isn't USB doesn't match by name alone
+        let pci = SerialPortInfo {
+            port_name: "/dev/ttyACM0".into(),
+            port_type: SerialPortType::PciPort,
+        };
+        assert!(!id.matches(&pci));
+        assert!(PortId::name("/dev/ttyACM0").matches(&pci));
+
+        // identical adapters, the one with the same name is preferred
+        let id = PortId::from(&usb("/dev/ttyUSB1", 0x5740, None));
+        let ports = [
+            usb("/dev/ttyUSB0", 0x5740, None),
+            usb("/dev/ttyUSB1", 0x5740, None),
+        ];
+        assert_eq!(id.find(&ports).unwrap().port_name, "/dev/ttyUSB1");
+        assert_eq!(id.find(&ports[..1]).unwrap().port_name, "/dev/ttyUSB0");
+    }
+
+    #[test]
+    fn serial_config() {
+        assert_eq!("0483:5740".parse(), Ok(UsbMatch(Some((0x0483, 0x5740)))));
+        assert_eq!("".parse(), Ok(UsbMatch(None)));
+        assert!("0483".parse::<UsbMatch>().is_err());
+        assert!("0483:57g0".parse::<UsbMatch>().is_err());
+        assert_eq!(UsbMatch(Some((0x0483, 0x5740))).to_string(), "0483:5740");
+
+        let ports = [
+            usb("/dev/ttyACM0", 0x5740, Some("A1")),
+            usb("/dev/ttyACM1", 0x5740, Some("B2")),
+            usb("/dev/ttyACM2", 0x0001, None),
+        ];
+        let mut config = SerialConfig::default();
+        assert_eq!(config.port_id(&ports), None);
+
+        // by name, but found again by its USB identity
+        config.port = "/dev/ttyACM2".into();
+        assert_eq!(config.port_id(&ports), Some(PortId::from(&ports[2])));
+        config.port = "/dev/pts/3".into();
+        assert_eq!(config.port_id(&ports), Some(PortId::name("/dev/pts/3")));
+
+        // by vid:pid, the name breaks ties and the serial number narrows it down
+        config.usb = "0483:5740".parse().unwrap();
+        assert_eq!(config.port_id(&ports), Some(PortId::from(&ports[0])));
+        config.port = "/dev/ttyACM1".into();
+        assert_eq!(config.port_id(&ports), Some(PortId::from(&ports[1])));
+        config.serial_number = "A1".into();
+        assert_eq!(config.port_id(&ports), Some(PortId::from(&ports[0])));
+
+        // not plugged in yet
+        config.serial_number = "C3".into();
+        let id = config.port_id(&ports).unwrap();
+        assert_eq!(id.find(&ports), None);
+        assert!(id.matches(&usb("/dev/ttyACM5", 0x5740, Some("C3"))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn serial_link() {
+        use serialport::TTYPort;
+
+        let wait_for = |link: &Link, connected: bool| {
+            let start = Instant::now();
+            while link.is_connected() != connected {
+                assert!(start.elapsed() < Duration::from_secs(5), "{}", link.state());
+                thread::sleep(Duration::from_millis(10));
             }
         };
 
-        break serialport::new(&port_info.port_name, baud).open();
+        let (mut mcu, host) = TTYPort::pair().unwrap();
+        let link = Link::spawn(PortId::name(host.name().unwrap()), &SerialConfig::default());
+        wait_for(&link, true);
+        drop(host);
+
+        let telemetry = Telemetry {
+            current: 1.,
+            ..Telemetry::default()
+        };
+        send(&mut mcu, &Packet::Telemetry(telemetry)).unwrap();
+        link.send(Packet::Command(0.5));
+
+        let mut frame = [0; HEADER_LEN + 4 + CRC_LEN];
+        mcu.set_timeout(Duration::from_secs(5)).unwrap();
+        mcu.read_exact(&mut frame).unwrap();
+        assert_eq!(Decoder::new().feed(&frame), [Ok(Packet::Command(0.5))]);
+
+        let start = Instant::now();
+        let received = loop {
+            if let Some(t) = link.telemetry().next() {
+                break t;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(received, telemetry);
+
+        // hanging up the other end looks like the adapter going away
+        drop(mcu);
+        wait_for(&link, false);
+        link.send(Packet::Command(0.6));
+        assert_eq!(link.stats().written, 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn serial_link_coil_off() {
+        let (mut mcu, host) = serialport::TTYPort::pair().unwrap();
+        let link = Link::spawn(PortId::name(host.name().unwrap()), &SerialConfig::default());
+        let start = Instant::now();
+        while !link.is_connected() {
+            assert!(start.elapsed() < Duration::from_secs(5), "{}", link.state());
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // going away, e.g. unwinding from a panic, turns the coil off
+        drop(link);
+        let mut frame = [0; HEADER_LEN + 1 + CRC_LEN];
+        mcu.set_timeout(Duration::from_secs(5)).unwrap();
+        mcu.read_exact(&mut frame).unwrap();
+        assert_eq!(Decoder::new().feed(&frame), [Ok(Packet::Enable(false))]);
+    }
+
+    #[test]
+    fn serial_errors() {
+        let mut frame = Packet::Command(0.5).encode();
+        frame[2] = VERSION + 1;
+        assert_eq!(
+            Decoder::new().feed(&frame)[0],
+            Err(DecodeError::Version(VERSION + 1))
+        );
+
+        let mut frame = vec![0xAA, 0x55, VERSION, 0x7F, 0];
+        frame.extend(crc16(&frame[2..]).to_be_bytes());
+        assert_eq!(
+            Decoder::new().feed(&frame),
+            [Err(DecodeError::UnknownType(0x7F))]
+        );
+
+        let mut frame = vec![0xAA, 0x55, VERSION, kind::COMMAND, 1, 0];
+        frame.extend(crc16(&frame[2..]).to_be_bytes());
+        assert_eq!(
+            Decoder::new().feed(&frame),
+            [Err(DecodeError::Payload {
+                kind: kind::COMMAND,
+                len: 1
+            })]
+        );
+
+        let frame = [0xAA, 0x55, VERSION, kind::COMMAND, 200];
+        assert_eq!(Decoder::new().feed(&frame), [Err(DecodeError::Length(200))]);
     }
 }
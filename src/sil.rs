@@ -0,0 +1,264 @@
+//! Software in the loop: the simulated plant is rendered into synthetic
+//! frames, the ball is found in them like in a camera frame, and the
+//! controller's command drives the plant again.
+//!
+//! The camera takes the place of the plant's own sensor model, so the
+//! plant's `latency` and `noise` are unused and the ones here apply.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use opencv::{core::Size, prelude::*};
+
+use crate::color::{Color, ColorRange};
+use crate::control::{Pid, PidConfig};
+use crate::plant::{Plant, PlantConfig};
+use crate::synth::{Disc, Scene};
+use crate::tracker::TrackerConfig;
+use crate::{Gap, Localisation, Tracker};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SilConfig {
+    pub fps: f64,
+    /// From capturing a frame to its detection reaching the controller, in seconds
+    pub latency: f64,
+    /// Seconds of simulated time in a run
+    pub duration: f64,
+    /// Where the ball starts, held still by the matching command, in mm
+    pub start_gap: f32,
+    pub width: i32,
+    pub height: i32,
+    /// Image scale, the inverse of `Calibration::scale`
+    pub px_per_mm: f32,
+    /// Magnet's row in the frame, the ball hangs straight under it
+    pub magnet_y: f32,
+    pub ball_radius: f32,
+    /// Exposure time, the ball smears by how far it moves in it, in seconds
+    pub exposure: f64,
+    /// Standard deviation of the pixel noise
+    pub noise: f64,
+}
+
+impl Default for SilConfig {
+    fn default() -> Self {
+        Self {
+            fps: 200.,
+            latency: 0.01,
+            duration: 3.,
+            start_gap: 20.,
+            width: 320,
+            height: 240,
+            px_per_mm: 4.,
+            magnet_y: 20.,
+            ball_radius: 8.5,
+            exposure: 0.002,
+            noise: 2.,
+        }
+    }
+}
+
+crate::section!(
+    SilConfig,
+    "sil",
+    [
+        fps,
+        latency,
+        duration,
+        start_gap,
+        width,
+        height,
+        px_per_mm,
+        magnet_y,
+        ball_radius,
+        exposure,
+        noise,
+    ]
+);
+
+/// One frame of a run
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Step {
+    /// When the frame was captured
+    pub time: Duration,
+    /// True gap when the frame was captured, in mm
+    pub gap: f32,
+    /// Gap the detector measured in this frame, in mm
+    pub measured: Option<f32>,
+    /// Command in effect after this frame
+    pub command: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Report {
+    pub steps: Vec<Step>,
+    pub setpoint: f32,
+    /// The ball hit the magnet or the floor at some point in the run
+    pub lost: bool,
+}
+
+impl Report {
+    pub fn detections(&self) -> usize {
+        self.steps.iter().filter(|s| s.measured.is_some()).count()
+    }
+
+    fn errors_after(&self, after: Duration) -> impl Iterator<Item = f32> + '_ {
+        self.steps
+            .iter()
+            .filter(move |s| s.time >= after)
+            .map(|s| s.gap - self.setpoint)
+    }
+
+    /// RMS distance from the setpoint, ignoring the first `after` of the run.
+    /// `None` if the run was no longer than that.
+    pub fn rms_error(&self, after: Duration) -> Option<f32> {
+        let (sum, n) = self
+            .errors_after(after)
+            .fold((0., 0), |(sum, n), e| (sum + e * e, n + 1));
+        (n > 0).then(|| (sum / n as f32).sqrt())
+    }
+
+    pub fn max_error(&self, after: Duration) -> f32 {
+        self.errors_after(after).fold(0., |max, e| max.max(e.abs()))
+    }
+
+    /// Mean absolute error of the detector against the true gap, in mm.
+    /// `None` if the ball was never detected.
+    pub fn measurement_error(&self) -> Option<f32> {
+        let (sum, n) = self
+            .steps
+            .iter()
+            .filter_map(|s| Some((s.measured? - s.gap).abs()))
+            .fold((0., 0), |(sum, n), e| (sum + e, n + 1));
+        (n > 0).then(|| sum / n as f32)
+    }
+}
+
+pub struct Harness {
+    config: SilConfig,
+    plant: Plant,
+    pid: Pid,
+    /// kept across frames like in the capture loop, searching around
+    /// the last detection
+    tracker: Tracker,
+    scene: Scene,
+    frame: Mat,
+    index: u64,
+    /// detections waiting out the latency, by capture time
+    in_flight: VecDeque<(Duration, Option<f32>)>,
+}
+
+impl Harness {
+    /// The controller's bias is replaced by the command holding the ball
+    /// at its setpoint
+    pub fn new(config: SilConfig, plant: PlantConfig, pid: PidConfig) -> Self {
+        let mut plant = Plant::new(plant, config.start_gap);
+        plant.hold(config.start_gap);
+
+        let pid = Pid::new(PidConfig {
+            bias: plant.equilibrium_command(pid.setpoint),
+            ..pid
+        });
+
+        let scene = Scene {
+            size: Size::new(config.width, config.height),
+            noise: config.noise,
+            ..Scene::default()
+        };
+        let color = scene.ball.unwrap().color;
+        let mut tracker = Tracker::new(ColorRange::from_tolerance(color, Color::new(60, 60, 60)));
+        tracker.set_config(&TrackerConfig {
+            localisation: Localisation::Moments,
+            ..TrackerConfig::default()
+        });
+
+        Self {
+            config,
+            plant,
+            pid,
+            tracker,
+            scene,
+            frame: Mat::default(),
+            index: 0,
+            in_flight: VecDeque::new(),
+        }
+    }
+
+    pub fn plant(&self) -> &Plant {
+        &self.plant
+    }
+
+    pub fn pid(&self) -> &Pid {
+        &self.pid
+    }
+
+    pub fn tracker(&self) -> &Tracker {
+        &self.tracker
+    }
+
+    /// Last rendered frame
+    pub fn frame(&self) -> &Mat {
+        &self.frame
+    }
+
+    /// Captures a frame, then advances the plant to the next one
+    pub fn step(&mut self) -> Step {
+        let c = self.config;
+        let time = self.plant.time();
+        let gap = self.plant.gap();
+
+        // image y grows downwards, like the gap
+        let y = c.magnet_y + gap * c.px_per_mm;
+        let smear = self.plant.velocity() * c.exposure as f32 * c.px_per_mm;
+        self.scene.ball = Some(Disc {
+            x: c.width as f32 / 2.,
+            y,
+            radius: c.ball_radius * c.px_per_mm,
+            ..self.scene.ball.unwrap()
+        });
+        self.scene.blur = (0., smear);
+        self.scene.seed = self.index;
+        self.scene.render(&mut self.frame);
+        self.index += 1;
+
+        let ball = self.tracker.track_at(&self.frame, time);
+        let measured = ball.map(|b| {
+            let magnet = (c.width as f32 / 2., c.magnet_y);
+            Gap::new(magnet, (b.x, b.y)).distance / c.px_per_mm
+        });
+        self.in_flight.push_back((time, measured));
+
+        // everything captured at least `latency` ago has arrived by now
+        let arrived = Duration::from_secs_f64(c.latency);
+        while let Some(&(captured, measured)) = self.in_flight.front() {
+            if captured + arrived > time {
+                break;
+            }
+            self.in_flight.pop_front();
+            if let Some(gap) = measured {
+                self.plant.set_command(self.pid.update(gap, captured));
+            }
+        }
+
+        self.plant.step(Duration::from_secs_f64(1. / c.fps));
+
+        Step {
+            time,
+            gap,
+            measured,
+            command: self.plant.command(),
+        }
+    }
+
+    /// Runs for the configured duration
+    pub fn run(&mut self) -> Report {
+        let frames = (self.config.duration * self.config.fps).round() as usize;
+        let steps: Vec<Step> = (0..frames).map(|_| self.step()).collect();
+
+        let floor = self.plant.config().floor as f32;
+        Report {
+            lost: steps.iter().any(|s| s.gap <= 0. || s.gap >= floor),
+            steps,
+            setpoint: self.pid.config().setpoint,
+        }
+    }
+}
@@ -0,0 +1,539 @@
+//! Where the pipeline's packets go: the microcontroller over serial, or
+//! another process over UDP, TCP or stdout, e.g. a controller running
+//! elsewhere or `nc` while testing.
+
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::serial::{Link, Packet, Telemetry, WriteStats, Writer};
+
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+// how often a server checks for new clients, and for being dropped
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(50);
+// a client that stops reading is dropped rather than stalling the others
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub trait Sink {
+    /// Hands `packet` on without blocking the capture loop
+    fn send(&mut self, packet: Packet);
+
+    /// Whether anything is on the other end, packets are discarded until then
+    fn is_connected(&self) -> bool;
+
+    /// One line for the status bar
+    fn state(&self) -> String;
+
+    fn stats(&self) -> WriteStats;
+
+    /// Write errors since the last call
+    fn errors(&mut self) -> Vec<io::Error>;
+
+    /// Telemetry received since the last call, if the other end sends any
+    fn telemetry(&mut self) -> Vec<Telemetry> {
+        Vec::new()
+    }
+}
+
+impl Sink for Link {
+    fn send(&mut self, packet: Packet) {
+        Link::send(self, packet)
+    }
+
+    fn is_connected(&self) -> bool {
+        Link::is_connected(self)
+    }
+
+    fn state(&self) -> String {
+        Link::state(self).to_string()
+    }
+
+    fn stats(&self) -> WriteStats {
+        Link::stats(self)
+    }
+
+    fn errors(&mut self) -> Vec<io::Error> {
+        Link::errors(self).collect()
+    }
+
+    fn telemetry(&mut self) -> Vec<Telemetry> {
+        Link::telemetry(self).collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SinkKind {
+    #[default]
+    Serial,
+    Udp,
+    /// Connects to `address`
+    Tcp,
+    /// Listens on `address`, sending to every client
+    TcpServer,
+    Stdout,
+}
+
+crate::keywords!(
+    SinkKind,
+    [
+        Serial => "serial",
+        Udp => "udp",
+        Tcp => "tcp",
+        TcpServer => "tcp_server",
+        Stdout => "stdout",
+    ]
+);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// The serial protocol's binary frames
+    Frames,
+    /// One JSON object per line, like `{"type":"command","value":0.42}`
+    #[default]
+    Json,
+}
+
+crate::keywords!(Format, [Frames => "frames", Json => "json"]);
+
+impl Format {
+    pub fn encode(self, packet: &Packet) -> Vec<u8> {
+        match self {
+            Self::Frames => packet.encode(),
+            Self::Json => {
+                let mut line = json(packet);
+                line.push('\n');
+                line.into_bytes()
+            }
+        }
+    }
+}
+
+fn json(packet: &Packet) -> String {
+    // JSON has no NaN or infinity
+    fn num(v: f32) -> String {
+        if v.is_finite() {
+            v.to_string()
+        } else {
+            "null".to_string()
+        }
+    }
+
+    match *packet {
+        Packet::Position(gap) => format!(
+            r#"{{"type":"position","distance":{},"offset":{}}}"#,
+            num(gap.distance),
+            num(gap.offset)
+        ),
+        Packet::Setpoint(v) => format!(r#"{{"type":"setpoint","value":{}}}"#, num(v)),
+        Packet::Gains { kp, ki, kd } => format!(
+            r#"{{"type":"gains","kp":{},"ki":{},"kd":{}}}"#,
+            num(kp),
+            num(ki),
+            num(kd)
+        ),
+        Packet::Command(v) => format!(r#"{{"type":"command","value":{}}}"#, num(v)),
+        Packet::Enable(on) => format!(r#"{{"type":"enable","on":{on}}}"#),
+        Packet::Heartbeat(sequence) => {
+            format!(r#"{{"type":"heartbeat","sequence":{sequence}}}"#)
+        }
+        Packet::Telemetry(t) => format!(
+            r#"{{"type":"telemetry","current":{},"duty":{},"loop_us":{},"faults":{}}}"#,
+            num(t.current),
+            num(t.duty),
+            t.loop_time.as_micros(),
+            t.faults.0
+        ),
+    }
+}
+
+/// Where the controller runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Controller {
+    /// The host's PID, its commands are sent
+    #[default]
+    Host,
+    /// The other end's, it's sent the ball's position along with the
+    /// setpoint and gains whenever they change
+    Remote,
+}
+
+crate::keywords!(Controller, [Host => "host", Remote => "remote"]);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SinkConfig {
+    pub kind: SinkKind,
+    /// `host:port` to send to, or to listen on for `tcp_server`.
+    /// `serial` uses the `[serial]` section instead.
+    pub address: String,
+    /// Ignored by `serial`, the microcontroller only reads frames
+    pub format: Format,
+    pub controller: Controller,
+}
+
+impl Default for SinkConfig {
+    fn default() -> Self {
+        Self {
+            kind: SinkKind::Serial,
+            address: "127.0.0.1:5005".to_string(),
+            format: Format::Json,
+            controller: Controller::Host,
+        }
+    }
+}
+
+crate::section!(SinkConfig, "sink", [kind, address, format, controller]);
+
+// the connected TCP peers, written to as one
+#[derive(Default)]
+struct Clients {
+    streams: Mutex<Vec<TcpStream>>,
+    // `streams` is taken out while being written to, this keeps counting
+    connected: AtomicUsize,
+}
+
+impl Clients {
+    fn add(&self, client: TcpStream) {
+        // best effort, these only fail on a dead socket and writing will tell
+        let _ = client.set_nodelay(true);
+        let _ = client.set_write_timeout(Some(WRITE_TIMEOUT));
+        self.streams.lock().unwrap().push(client);
+        self.connected.fetch_add(1, SeqCst);
+    }
+
+    fn len(&self) -> usize {
+        self.connected.load(SeqCst)
+    }
+}
+
+struct Broadcast(Arc<Clients>);
+
+impl Write for Broadcast {
+    /// Never fails, a client that does is dropped
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // written without the lock, a slow client doesn't block adding others
+        let mut streams = std::mem::take(&mut *self.0.streams.lock().unwrap());
+        let before = streams.len();
+        streams.retain_mut(|client| client.write_all(buf).is_ok());
+        self.0.connected.fetch_sub(before - streams.len(), SeqCst);
+
+        // clients added meanwhile go after the ones already there
+        let mut current = self.0.streams.lock().unwrap();
+        streams.append(&mut current);
+        *current = streams;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Datagrams(UdpSocket);
+
+impl Write for Datagrams {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0.send(buf) {
+            // an earlier datagram found nobody listening, which is fine
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(buf.len()),
+            res => res,
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps connecting to `address` while there's no connection,
+/// until the sink is dropped
+fn keep_connected(address: String, clients: Weak<Clients>) {
+    while let Some(clients) = clients.upgrade() {
+        if clients.len() == 0 {
+            let connected = address.to_socket_addrs().ok().and_then(|mut addrs| {
+                addrs.find_map(|addr| TcpStream::connect_timeout(&addr, RETRY_INTERVAL).ok())
+            });
+            if let Some(stream) = connected {
+                clients.add(stream);
+            }
+        }
+        drop(clients);
+        thread::sleep(RETRY_INTERVAL);
+    }
+}
+
+/// Accepts clients until the sink is dropped, which closes the listener
+fn accept(listener: TcpListener, clients: Weak<Clients>) {
+    // polled, a blocking accept would only notice the sink is gone when
+    // the next client connects
+    if let Err(e) = listener.set_nonblocking(true) {
+        eprintln!("failed to accept connections: {e}");
+        return;
+    }
+
+    while let Some(clients) = clients.upgrade() {
+        match listener.accept() {
+            Ok((client, _)) => match client.set_nonblocking(false) {
+                Ok(()) => clients.add(client),
+                Err(e) => eprintln!("failed to accept a connection: {e}"),
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                drop(clients);
+                thread::sleep(ACCEPT_INTERVAL);
+            }
+            Err(e) => eprintln!("failed to accept a connection: {e}"),
+        }
+    }
+}
+
+/// Every sink but serial, written by a `Writer` so a slow peer never
+/// holds up the capture loop
+pub struct Stream {
+    writer: Writer,
+    kind: SinkKind,
+    address: Option<SocketAddr>,
+    /// `None` if there's always someone, as far as we can tell
+    clients: Option<Arc<Clients>>,
+}
+
+impl Stream {
+    /// Anything but `SinkKind::Serial`, which needs a `Link`
+    pub fn open(config: &SinkConfig) -> io::Result<Self> {
+        let format = config.format;
+        let encode = move |packet: &Packet| format.encode(packet);
+
+        let (writer, address, clients) = match config.kind {
+            SinkKind::Serial => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "a serial sink is a serial::Link",
+                ))
+            }
+            SinkKind::Stdout => (Writer::with_encoder(io::stdout(), encode), None, None),
+            SinkKind::Udp => {
+                let peer = config
+                    .address
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+                let local: SocketAddr = if peer.is_ipv4() {
+                    "0.0.0.0:0".parse().unwrap()
+                } else {
+                    "[::]:0".parse().unwrap()
+                };
+                let socket = UdpSocket::bind(local)?;
+                socket.connect(peer)?;
+                let writer = Writer::with_encoder(Datagrams(socket), encode);
+                (writer, Some(peer), None)
+            }
+            SinkKind::Tcp => {
+                let clients = Arc::new(Clients::default());
+                let (address, weak) = (config.address.clone(), Arc::downgrade(&clients));
+                thread::spawn(move || keep_connected(address, weak));
+                let writer = Writer::with_encoder(Broadcast(clients.clone()), encode);
+                (writer, None, Some(clients))
+            }
+            SinkKind::TcpServer => {
+                let listener = TcpListener::bind(&config.address)?;
+                let address = listener.local_addr()?;
+                let clients = Arc::new(Clients::default());
+                let weak = Arc::downgrade(&clients);
+                thread::spawn(move || accept(listener, weak));
+                let writer = Writer::with_encoder(Broadcast(clients.clone()), encode);
+                (writer, Some(address), Some(clients))
+            }
+        };
+
+        Ok(Self {
+            writer,
+            kind: config.kind,
+            address,
+            clients,
+        })
+    }
+
+    /// The peer for `udp`, the bound address for `tcp_server`
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.address
+    }
+}
+
+impl Sink for Stream {
+    fn send(&mut self, packet: Packet) {
+        if self.is_connected() {
+            self.writer.send(packet);
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.clients.as_ref().map_or(true, |c| c.len() > 0)
+    }
+
+    fn state(&self) -> String {
+        let clients = self.clients.as_ref().map_or(0, |c| c.len());
+        match (self.kind, self.address) {
+            (SinkKind::TcpServer, Some(address)) => {
+                format!("tcp: {clients} clients on {address}")
+            }
+            (SinkKind::Tcp, _) if clients == 0 => "tcp: connecting".to_string(),
+            (SinkKind::Tcp, _) => "tcp: connected".to_string(),
+            (SinkKind::Udp, Some(address)) => format!("udp: sending to {address}"),
+            (kind, _) => kind.to_string(),
+        }
+    }
+
+    fn stats(&self) -> WriteStats {
+        self.writer.stats()
+    }
+
+    fn errors(&mut self) -> Vec<io::Error> {
+        self.writer.errors().collect()
+    }
+}
+
+impl Drop for Stream {
+    /// Whatever's on the other end should stop driving the coil too
+    fn drop(&mut self) {
+        self.send(Packet::Enable(false));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::{Decoder, Faults};
+    use crate::Gap;
+    use std::io::{BufRead, BufReader};
+    use std::time::Instant;
+
+    fn wait_until(done: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !done() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn config(kind: SinkKind, address: &str, format: Format) -> SinkConfig {
+        SinkConfig {
+            kind,
+            address: address.to_string(),
+            format,
+            ..SinkConfig::default()
+        }
+    }
+
+    #[test]
+    fn sink_json() {
+        let lines: Vec<String> = [
+            Packet::Position(Gap {
+                distance: 14.5,
+                offset: -0.25,
+            }),
+            Packet::Setpoint(15.),
+            Packet::Gains {
+                kp: 0.015,
+                ki: 0.1,
+                kd: f32::NAN,
+            },
+            Packet::Command(0.42),
+            Packet::Enable(true),
+            Packet::Heartbeat(7),
+            Packet::Telemetry(Telemetry {
+                current: 1.25,
+                duty: 0.5,
+                loop_time: Duration::from_micros(250),
+                faults: Faults::WATCHDOG,
+            }),
+        ]
+        .iter()
+        .map(|p| String::from_utf8(Format::Json.encode(p)).unwrap())
+        .collect();
+
+        assert_eq!(
+            lines,
+            [
+                "{\"type\":\"position\",\"distance\":14.5,\"offset\":-0.25}\n",
+                "{\"type\":\"setpoint\",\"value\":15}\n",
+                "{\"type\":\"gains\",\"kp\":0.015,\"ki\":0.1,\"kd\":null}\n",
+                "{\"type\":\"command\",\"value\":0.42}\n",
+                "{\"type\":\"enable\",\"on\":true}\n",
+                "{\"type\":\"heartbeat\",\"sequence\":7}\n",
+                "{\"type\":\"telemetry\",\"current\":1.25,\"duty\":0.5,\"loop_us\":250,\"faults\":16}\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn sink_udp() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let address = receiver.local_addr().unwrap().to_string();
+
+        let mut sink = Stream::open(&config(SinkKind::Udp, &address, Format::Frames)).unwrap();
+        assert!(sink.is_connected());
+        sink.send(Packet::Command(0.5));
+
+        // one packet per datagram
+        let mut buf = [0; 64];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(Decoder::new().feed(&buf[..n]), [Ok(Packet::Command(0.5))]);
+
+        drop(sink);
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(Decoder::new().feed(&buf[..n]), [Ok(Packet::Enable(false))]);
+    }
+
+    #[test]
+    fn sink_tcp_server() {
+        let mut sink =
+            Stream::open(&config(SinkKind::TcpServer, "127.0.0.1:0", Format::Json)).unwrap();
+        assert!(!sink.is_connected());
+        // nobody to send to yet
+        sink.send(Packet::Command(0.1));
+
+        let address = sink.address().unwrap();
+        let clients: Vec<_> = (0..2)
+            .map(|_| BufReader::new(TcpStream::connect(address).unwrap()))
+            .collect();
+        wait_until(|| sink.state() == format!("tcp: 2 clients on {address}"));
+
+        sink.send(Packet::Command(0.2));
+        for mut client in clients {
+            let mut line = String::new();
+            client.read_line(&mut line).unwrap();
+            assert_eq!(line, "{\"type\":\"command\",\"value\":0.2}\n");
+        }
+        // gone, which a write or two notices
+        let start = Instant::now();
+        while sink.is_connected() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            sink.send(Packet::Command(0.3));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(sink.stats().errors, 0);
+
+        // stops listening without waiting for another client
+        drop(sink);
+        wait_until(|| TcpStream::connect(address).is_err());
+    }
+
+    #[test]
+    fn sink_tcp_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let mut sink = Stream::open(&config(SinkKind::Tcp, &address, Format::Json)).unwrap();
+
+        let (peer, _) = listener.accept().unwrap();
+        wait_until(|| sink.is_connected());
+        sink.send(Packet::Heartbeat(1));
+
+        let mut line = String::new();
+        BufReader::new(peer).read_line(&mut line).unwrap();
+        assert_eq!(line, "{\"type\":\"heartbeat\",\"sequence\":1}\n");
+    }
+}
@@ -0,0 +1,384 @@
+//! Where frames come from, so the same pipeline runs on the live camera,
+//! on recordings and in tests.
+
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use cv::{
+    core::Size,
+    prelude::*,
+    videoio::{self, VideoCapture},
+};
+use opencv as cv;
+
+use crate::synth::Scene;
+
+#[cfg(target_os = "linux")]
+pub const CAP_BACKEND: i32 = videoio::CAP_V4L2;
+#[cfg(not(target_os = "linux"))]
+pub const CAP_BACKEND: i32 = videoio::CAP_ANY;
+
+pub trait FrameSource {
+    /// Reads the next frame into `frame` and returns when it was captured,
+    /// relative to the start of the source. `None` if no frame was available,
+    /// errors are logged.
+    fn read(&mut self, frame: &mut Mat) -> Option<Duration>;
+
+    /// A recording that was read to the end, `read` won't return anything
+    /// from now on
+    fn is_finished(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SourceKind {
+    #[default]
+    Camera,
+    Video,
+    Images,
+    Synthetic,
+}
+
+crate::keywords!(
+    SourceKind,
+    [
+        Camera => "camera",
+        Video => "video",
+        Images => "images",
+        Synthetic => "synthetic",
+    ]
+);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceConfig {
+    pub kind: SourceKind,
+    /// Camera index or device path, video file, or image directory
+    pub path: String,
+    /// 0 keeps the camera's default. Sets the size of synthetic frames,
+    /// recordings keep the size they were recorded at.
+    pub width: i32,
+    pub height: i32,
+    /// 0 keeps the camera's default. Sets the timestamps of image
+    /// directories and synthetic frames.
+    pub fps: f64,
+    /// Camera pixel format like `MJPG`, empty keeps the default
+    pub fourcc: String,
+}
+
+impl Default for SourceConfig {
+    fn default() -> Self {
+        Self {
+            kind: SourceKind::Camera,
+            path: "0".to_string(),
+            width: 0,
+            height: 0,
+            fps: 0.,
+            fourcc: String::new(),
+        }
+    }
+}
+
+crate::section!(
+    SourceConfig,
+    "source",
+    [kind, path, width, height, fps, fourcc]
+);
+
+impl SourceConfig {
+    fn fps_or(&self, default: f64) -> f64 {
+        if self.fps > 0. {
+            self.fps
+        } else {
+            default
+        }
+    }
+
+    fn size_or(&self, default: Size) -> Size {
+        if self.width > 0 && self.height > 0 {
+            Size::new(self.width, self.height)
+        } else {
+            default
+        }
+    }
+}
+
+pub fn open(config: &SourceConfig) -> cv::Result<Box<dyn FrameSource>> {
+    Ok(match config.kind {
+        SourceKind::Camera => Box::new(Camera::open(config)?),
+        SourceKind::Video => Box::new(VideoFile::open(&config.path)?),
+        SourceKind::Images => Box::new(ImageDir::open(&config.path, config.fps_or(30.))?),
+        SourceKind::Synthetic => Box::new(Synthetic {
+            scene: Scene {
+                size: config.size_or(Scene::default().size),
+                ..Scene::default()
+            },
+            fps: config.fps_or(60.),
+            ..Synthetic::default()
+        }),
+    })
+}
+
+fn not_opened(what: &str) -> cv::Error {
+    cv::Error::new(cv::core::StsError, format!("failed to open {what}"))
+}
+
+pub struct Camera {
+    cap: VideoCapture,
+    start: Instant,
+}
+
+impl Camera {
+    pub fn open(config: &SourceConfig) -> cv::Result<Self> {
+        let mut cap = match config.path.parse::<i32>() {
+            Ok(index) => VideoCapture::new(index, CAP_BACKEND)?,
+            Err(_) => VideoCapture::from_file(&config.path, CAP_BACKEND)?,
+        };
+        if !cap.is_opened()? {
+            return Err(not_opened(&format!("camera {}", config.path)));
+        }
+
+        // the format has to be set first, it limits the sizes and rates available
+        if let &[a, b, c, d] = config.fourcc.as_bytes() {
+            let fourcc = videoio::VideoWriter::fourcc(a as i8, b as i8, c as i8, d as i8)?;
+            cap.set(videoio::CAP_PROP_FOURCC, fourcc as f64)?;
+        }
+        if config.width > 0 && config.height > 0 {
+            cap.set(videoio::CAP_PROP_FRAME_WIDTH, config.width as f64)?;
+            cap.set(videoio::CAP_PROP_FRAME_HEIGHT, config.height as f64)?;
+        }
+        if config.fps > 0. {
+            cap.set(videoio::CAP_PROP_FPS, config.fps)?;
+        }
+
+        Ok(Self {
+            cap,
+            start: Instant::now(),
+        })
+    }
+}
+
+impl FrameSource for Camera {
+    fn read(&mut self, frame: &mut Mat) -> Option<Duration> {
+        match self.cap.read(frame) {
+            Ok(grabbed) => grabbed.then(|| self.start.elapsed()),
+            Err(e) => {
+                eprintln!("failed to read from the camera: {e}");
+                None
+            }
+        }
+    }
+}
+
+pub struct VideoFile {
+    cap: VideoCapture,
+    finished: bool,
+}
+
+impl VideoFile {
+    pub fn open(path: &str) -> cv::Result<Self> {
+        let cap = VideoCapture::from_file(path, videoio::CAP_ANY)?;
+        if !cap.is_opened()? {
+            return Err(not_opened(path));
+        }
+
+        Ok(Self {
+            cap,
+            finished: false,
+        })
+    }
+}
+
+impl FrameSource for VideoFile {
+    fn read(&mut self, frame: &mut Mat) -> Option<Duration> {
+        let grabbed = self.cap.read(frame).unwrap_or_else(|e| {
+            eprintln!("failed to read the video: {e}");
+            false
+        });
+        if !grabbed {
+            // after an error the rest of the file is unlikely to be any better
+            self.finished = true;
+            return None;
+        }
+
+        let msec = self.cap.get(videoio::CAP_PROP_POS_MSEC).ok()?;
+        Some(Duration::from_secs_f64(msec.max(0.) / 1000.))
+    }
+
+    fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+/// Every image in a directory, in file name order
+pub struct ImageDir {
+    paths: Vec<PathBuf>,
+    next: usize,
+    fps: f64,
+}
+
+impl ImageDir {
+    pub fn open(path: &str, fps: f64) -> cv::Result<Self> {
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(path)
+            .map_err(|_| not_opened(path))?
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|p| p.is_file())
+            .collect();
+        paths.sort();
+
+        Ok(Self {
+            paths,
+            next: 0,
+            fps,
+        })
+    }
+}
+
+impl FrameSource for ImageDir {
+    fn read(&mut self, frame: &mut Mat) -> Option<Duration> {
+        // skips anything that isn't an image
+        while let Some(path) = self.paths.get(self.next) {
+            let index = self.next;
+            self.next += 1;
+
+            let image =
+                match cv::imgcodecs::imread(&path.to_string_lossy(), cv::imgcodecs::IMREAD_COLOR) {
+                    Ok(image) => image,
+                    Err(e) => {
+                        eprintln!("failed to read {}: {e}", path.display());
+                        continue;
+                    }
+                };
+            if !image.empty() {
+                *frame = image;
+                return Some(Duration::from_secs_f64(index as f64 / self.fps));
+            }
+        }
+        None
+    }
+
+    fn is_finished(&self) -> bool {
+        self.next >= self.paths.len()
+    }
+}
+
+/// A ball bobbing up and down through a synthetic scene
+#[derive(Debug, Clone, PartialEq)]
+pub struct Synthetic {
+    /// Everything but the ball's position, which is set every frame
+    pub scene: Scene,
+    pub fps: f64,
+    /// Peak distance from the center of the frame, in pixels
+    pub amplitude: f64,
+    /// Seconds per oscillation
+    pub period: f64,
+    pub frame: u64,
+}
+
+impl Default for Synthetic {
+    fn default() -> Self {
+        Self {
+            scene: Scene::default(),
+            fps: 60.,
+            amplitude: 100.,
+            period: 2.,
+            frame: 0,
+        }
+    }
+}
+
+impl Synthetic {
+    /// Where the ball is at `t` seconds
+    pub fn position(&self, t: f64) -> (f64, f64) {
+        let phase = 2. * std::f64::consts::PI * t / self.period;
+        let size = self.scene.size;
+        (
+            size.width as f64 / 2.,
+            size.height as f64 / 2. + self.amplitude * phase.sin(),
+        )
+    }
+}
+
+impl FrameSource for Synthetic {
+    fn read(&mut self, frame: &mut Mat) -> Option<Duration> {
+        let t = self.frame as f64 / self.fps;
+        self.frame += 1;
+
+        let (x, y) = self.position(t);
+        if let Some(ball) = &mut self.scene.ball {
+            ball.x = x as f32;
+            ball.y = y as f32;
+        }
+        self.scene.render(frame);
+
+        Some(Duration::from_secs_f64(t))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cv::core::{Scalar, Vector, CV_8UC3};
+
+    #[test]
+    fn source_image_dir() {
+        let dir = std::env::temp_dir().join(format!("levitation-images-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // written out of order, told apart by their width
+        for (name, width) in [("c.png", 3), ("a.png", 1), ("b.png", 2)] {
+            let image =
+                Mat::new_rows_cols_with_default(4, width, CV_8UC3, Scalar::all(0.)).unwrap();
+            let path = dir.join(name);
+            cv::imgcodecs::imwrite(&path.to_string_lossy(), &image, &Vector::new()).unwrap();
+        }
+        std::fs::write(dir.join("b.txt"), "not an image").unwrap();
+
+        let mut source = ImageDir::open(&dir.to_string_lossy(), 10.).unwrap();
+        let mut frame = Mat::default();
+        let mut read = Vec::new();
+        while let Some(t) = source.read(&mut frame) {
+            read.push((frame.cols(), t));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // the text file is skipped, keeping its place in the timeline
+        assert_eq!(
+            read,
+            [
+                (1, Duration::ZERO),
+                (2, Duration::from_millis(100)),
+                (3, Duration::from_millis(300)),
+            ]
+        );
+        assert!(source.is_finished());
+        assert_eq!(source.read(&mut frame), None);
+    }
+
+    #[test]
+    fn source_image_dir_missing() {
+        assert!(ImageDir::open("no such directory", 30.).is_err());
+    }
+
+    #[test]
+    fn source_synthetic() {
+        let mut source = Synthetic {
+            fps: 4.,
+            ..Synthetic::default()
+        };
+        let size = source.scene.size;
+        let center = (size.width as f64 / 2., size.height as f64 / 2.);
+        assert_eq!(source.position(0.), center);
+        let (x, y) = source.position(source.period / 4.);
+        assert_eq!(x, center.0);
+        assert!((y - center.1 - source.amplitude).abs() < 1e-9);
+
+        let mut frame = Mat::default();
+        assert_eq!(source.read(&mut frame), Some(Duration::ZERO));
+        assert_eq!(source.read(&mut frame), Some(Duration::from_millis(250)));
+        assert_eq!(frame.size().unwrap(), size);
+        assert!(!source.is_finished());
+    }
+}
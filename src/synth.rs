@@ -0,0 +1,156 @@
+//! Synthetic frames with a ball at a known position, so detection can be
+//! tested against the ground truth without a camera.
+
+use cv::{
+    core::{Point, Scalar, Size, Vec3f, CV_32FC3, CV_8UC3, RNG, RNG_NORMAL},
+    imgproc,
+    prelude::*,
+};
+use opencv as cv;
+
+use crate::color::Color;
+
+// fractional bits of the coordinates passed to `circle`
+const SHIFT: i32 = 8;
+const MAX_BLUR_STEPS: usize = 32;
+
+/// A filled circle, in image coordinates where integers are pixel centers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Disc {
+    pub x: f32,
+    pub y: f32,
+    pub radius: f32,
+    pub color: Color,
+}
+
+impl Disc {
+    pub fn new(x: f32, y: f32, radius: f32, color: Color) -> Self {
+        Self {
+            x,
+            y,
+            radius,
+            color,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scene {
+    pub size: Size,
+    pub background: Color,
+    /// Where the ball is halfway through the exposure
+    pub ball: Option<Disc>,
+    /// Other blobs, which stay still during the exposure
+    pub distractors: Vec<Disc>,
+    /// How far the ball moves during the exposure, in pixels
+    pub blur: (f32, f32),
+    /// Brightness change from the left edge to the right one,
+    /// e.g. 0.5 goes from 75% to 125%
+    pub gradient: f32,
+    /// Standard deviation of the gaussian noise added to every channel
+    pub noise: f64,
+    /// Same seed, same noise
+    pub seed: u64,
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Self {
+            size: Size::new(640, 480),
+            background: Color::new(40, 40, 40),
+            ball: Some(Disc::new(320., 240., 20., Color::new(230, 40, 40))),
+            distractors: Vec::new(),
+            blur: (0., 0.),
+            gradient: 0.,
+            noise: 0.,
+            seed: 0,
+        }
+    }
+}
+
+fn bgr(color: Color) -> Scalar {
+    Scalar::new(color.b() as f64, color.g() as f64, color.r() as f64, 0.)
+}
+
+fn draw_disc(img: &mut Mat, disc: &Disc, (dx, dy): (f32, f32)) {
+    let fixed = |v: f32| (v * (1 << SHIFT) as f32).round() as i32;
+
+    imgproc::circle(
+        img,
+        Point::new(fixed(disc.x + dx), fixed(disc.y + dy)),
+        fixed(disc.radius),
+        bgr(disc.color),
+        imgproc::FILLED,
+        imgproc::LINE_AA,
+        SHIFT,
+    )
+    .unwrap();
+}
+
+impl Scene {
+    /// Renders the scene into `dst` as a BGR image
+    pub fn render(&self, dst: &mut Mat) {
+        let blur = self.blur.0.hypot(self.blur.1);
+        let steps = (blur.ceil() as usize).clamp(1, MAX_BLUR_STEPS);
+
+        // motion blur is the average of the ball at evenly spaced
+        // points of its path, centered on its position
+        let mut sharp = Mat::default();
+        let mut sum = Mat::new_size_with_default(self.size, CV_32FC3, Scalar::all(0.)).unwrap();
+        for i in 0..steps {
+            let t = (i as f32 + 0.5) / steps as f32 - 0.5;
+            self.draw(&mut sharp, (self.blur.0 * t, self.blur.1 * t));
+            imgproc::accumulate(&sharp, &mut sum, &cv::core::no_array()).unwrap();
+        }
+
+        let mut lit = Mat::default();
+        if self.gradient != 0. {
+            let width = self.size.width;
+            let row: Vec<Vec3f> = (0..width)
+                .map(|x| {
+                    let across = (x as f32 + 0.5) / width as f32 - 0.5;
+                    Vec3f::all(1. + self.gradient * across)
+                })
+                .collect();
+            let ramp =
+                cv::core::repeat(&Mat::from_slice(&row).unwrap(), self.size.height, 1).unwrap();
+
+            cv::core::multiply(&sum, &ramp, &mut lit, 1. / steps as f64, -1).unwrap();
+        } else {
+            sum.convert_to(&mut lit, CV_32FC3, 1. / steps as f64, 0.)
+                .unwrap();
+        }
+
+        if self.noise > 0. {
+            let mut noise =
+                Mat::new_size_with_default(self.size, CV_32FC3, Scalar::all(0.)).unwrap();
+            RNG::new(self.seed)
+                .unwrap()
+                .fill(
+                    &mut noise,
+                    RNG_NORMAL,
+                    &Scalar::all(0.),
+                    &Scalar::all(self.noise),
+                    false,
+                )
+                .unwrap();
+            cv::core::add(&lit, &noise, &mut sum, &cv::core::no_array(), -1).unwrap();
+            std::mem::swap(&mut lit, &mut sum);
+        }
+
+        // saturates back to 0..=255
+        lit.convert_to(dst, CV_8UC3, 1., 0.).unwrap();
+    }
+
+    // one instant of the exposure, with the ball moved by `offset`
+    fn draw(&self, img: &mut Mat, offset: (f32, f32)) {
+        *img = Mat::new_size_with_default(self.size, CV_8UC3, bgr(self.background)).unwrap();
+
+        for disc in &self.distractors {
+            draw_disc(img, disc, (0., 0.));
+        }
+        if let Some(ball) = &self.ball {
+            draw_disc(img, ball, offset);
+        }
+    }
+}
@@ -0,0 +1,582 @@
+use crate::{threshold_mask, Ball, MaskBuffers, Threshold};
+
+use std::{
+    f32::consts::PI,
+    time::{Duration, Instant},
+};
+
+use cv::{
+    core::{KeyPoint, Point, Ptr, Rect, Scalar, Size, Vector, CV_8UC1},
+    features2d::{SimpleBlobDetector, SimpleBlobDetector_Params},
+    prelude::*,
+};
+use opencv as cv;
+
+/// Settings for the `SimpleBlobDetector`, see its OpenCV docs for details.
+///
+/// Defaults are OpenCV's, except every filter starts disabled.
+/// Enabling them is what rejects reflections and cables of the same color.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlobParams {
+    pub min_threshold: f32,
+    pub max_threshold: f32,
+    pub threshold_step: f32,
+    pub min_repeatability: usize,
+    pub min_dist_between_blobs: f32,
+
+    pub filter_by_area: bool,
+    pub min_area: f32,
+    pub max_area: f32,
+
+    pub filter_by_circularity: bool,
+    pub min_circularity: f32,
+    pub max_circularity: f32,
+
+    pub filter_by_convexity: bool,
+    pub min_convexity: f32,
+    pub max_convexity: f32,
+
+    pub filter_by_inertia: bool,
+    pub min_inertia_ratio: f32,
+    pub max_inertia_ratio: f32,
+}
+
+impl Default for BlobParams {
+    fn default() -> Self {
+        Self {
+            min_threshold: 50.,
+            max_threshold: 220.,
+            threshold_step: 10.,
+            min_repeatability: 2,
+            min_dist_between_blobs: 10.,
+
+            filter_by_area: false,
+            min_area: 25.,
+            max_area: f32::INFINITY,
+
+            filter_by_circularity: false,
+            min_circularity: 0.8,
+            max_circularity: f32::INFINITY,
+
+            filter_by_convexity: false,
+            min_convexity: 0.95,
+            max_convexity: f32::INFINITY,
+
+            filter_by_inertia: false,
+            min_inertia_ratio: 0.1,
+            max_inertia_ratio: f32::INFINITY,
+        }
+    }
+}
+
+crate::section!(
+    BlobParams,
+    "blob",
+    [
+        min_threshold,
+        max_threshold,
+        threshold_step,
+        min_repeatability,
+        min_dist_between_blobs,
+        filter_by_area,
+        min_area,
+        max_area,
+        filter_by_circularity,
+        min_circularity,
+        max_circularity,
+        filter_by_convexity,
+        min_convexity,
+        max_convexity,
+        filter_by_inertia,
+        min_inertia_ratio,
+        max_inertia_ratio,
+    ]
+);
+
+impl BlobParams {
+    pub fn to_cv(&self) -> SimpleBlobDetector_Params {
+        SimpleBlobDetector_Params {
+            // the mask is white where the color matched
+            filter_by_color: true,
+            blob_color: 255,
+
+            min_threshold: self.min_threshold,
+            max_threshold: self.max_threshold,
+            threshold_step: self.threshold_step,
+            min_repeatability: self.min_repeatability,
+            min_dist_between_blobs: self.min_dist_between_blobs,
+
+            filter_by_area: self.filter_by_area,
+            min_area: self.min_area,
+            max_area: self.max_area,
+
+            filter_by_circularity: self.filter_by_circularity,
+            min_circularity: self.min_circularity,
+            max_circularity: self.max_circularity,
+
+            filter_by_convexity: self.filter_by_convexity,
+            min_convexity: self.min_convexity,
+            max_convexity: self.max_convexity,
+
+            filter_by_inertia: self.filter_by_inertia,
+            min_inertia_ratio: self.min_inertia_ratio,
+            max_inertia_ratio: self.max_inertia_ratio,
+
+            ..SimpleBlobDetector_Params::default().unwrap()
+        }
+    }
+}
+
+/// Which blob is the ball when the detector finds more than one.
+///
+/// Policies needing information the tracker doesn't have yet
+/// (no previous detection, no magnet selected) fall back to `Largest`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Selection {
+    #[default]
+    Largest,
+    /// Closest to the ball found in the previous frame
+    ClosestToPrevious,
+    ClosestToMagnet,
+    HighestConfidence,
+}
+
+crate::keywords!(
+    Selection,
+    [
+        Largest => "largest",
+        ClosestToPrevious => "closest_to_previous",
+        ClosestToMagnet => "closest_to_magnet",
+        HighestConfidence => "highest_confidence",
+    ]
+);
+
+/// How the ball's center is measured once its blob is found
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Localisation {
+    /// The blob detector's keypoint, coarse and jittery
+    #[default]
+    Keypoint,
+    /// Centroid of the matching pixels around the keypoint, sub-pixel
+    Moments,
+    /// Like `Moments`, weighting each pixel by its brightness in the frame
+    WeightedMoments,
+}
+
+crate::keywords!(
+    Localisation,
+    [
+        Keypoint => "keypoint",
+        Moments => "moments",
+        WeightedMoments => "weighted_moments",
+    ]
+);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrackerConfig {
+    pub selection: Selection,
+    pub localisation: Localisation,
+
+    /// Only search a window around the predicted position
+    /// once the ball has been found confidently
+    pub roi: bool,
+    /// Pixels between the edge of the ball and the edge of the window
+    pub roi_margin: f32,
+    /// Detections below this confidence don't move the window
+    pub roi_min_confidence: f32,
+    /// Frames without a confident detection before going back
+    /// to searching the whole frame
+    pub roi_max_misses: u32,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            selection: Selection::default(),
+            localisation: Localisation::default(),
+            roi: true,
+            roi_margin: 40.,
+            roi_min_confidence: 0.5,
+            roi_max_misses: 5,
+        }
+    }
+}
+
+crate::section!(
+    TrackerConfig,
+    "tracker",
+    [
+        selection,
+        localisation,
+        roi,
+        roi_margin,
+        roi_min_confidence,
+        roi_max_misses,
+    ]
+);
+
+/// Every blob found in a frame, and which one was picked as the ball
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Detection {
+    pub candidates: Vec<Ball>,
+    pub chosen: Option<usize>,
+}
+
+impl Detection {
+    pub fn ball(&self) -> Option<Ball> {
+        self.chosen.map(|i| self.candidates[i])
+    }
+}
+
+fn create_blob_detector(params: &BlobParams) -> Ptr<SimpleBlobDetector> {
+    SimpleBlobDetector::create(params.to_cv()).unwrap()
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct RoiState {
+    enabled: bool,
+    margin: f32,
+    min_confidence: f32,
+    max_misses: u32,
+
+    /// last confident detection
+    anchor: Option<Ball>,
+    misses: u32,
+    prediction: Option<(f32, f32)>,
+    window: Option<Rect>,
+}
+
+impl RoiState {
+    fn window(&self, frame: Size) -> Option<Rect> {
+        let anchor = self.anchor.filter(|_| self.enabled)?;
+        let (x, y) = self.prediction.unwrap_or((anchor.x, anchor.y));
+        let half = anchor.radius + self.margin;
+
+        let window = Rect::new(
+            (x - half).floor() as i32,
+            (y - half).floor() as i32,
+            (2. * half).ceil() as i32,
+            (2. * half).ceil() as i32,
+        ) & Rect::new(0, 0, frame.width, frame.height);
+
+        (window.width > 0 && window.height > 0).then_some(window)
+    }
+
+    fn record(&mut self, ball: Option<Ball>) {
+        match ball.filter(|b| b.confidence >= self.min_confidence) {
+            Some(ball) => {
+                self.anchor = Some(ball);
+                self.misses = 0;
+            }
+            None => {
+                self.misses += 1;
+                if self.misses > self.max_misses {
+                    self.anchor = None;
+                }
+            }
+        }
+        self.prediction = None;
+    }
+}
+
+/// Finds the ball in consecutive frames.
+///
+/// Owns the blob detector and every intermediate image, so calling `track`
+/// in the capture loop doesn't allocate once the frame size is stable.
+pub struct Tracker {
+    threshold: Threshold,
+    params: BlobParams,
+    detector: Ptr<SimpleBlobDetector>,
+
+    buffers: MaskBuffers,
+    mask: Mat,
+    keypoints: Vector<KeyPoint>,
+    gray: Mat,
+    weighted: Mat,
+
+    selection: Selection,
+    localisation: Localisation,
+    magnet: Option<(f32, f32)>,
+    previous: Option<Ball>,
+
+    roi: RoiState,
+    frame_size: Size,
+    detection: Detection,
+    start: Instant,
+}
+
+impl Tracker {
+    pub fn new(threshold: impl Into<Threshold>) -> Self {
+        Self::with_params(threshold, BlobParams::default())
+    }
+
+    pub fn with_params(threshold: impl Into<Threshold>, params: BlobParams) -> Self {
+        Self {
+            threshold: threshold.into(),
+            params,
+            detector: create_blob_detector(&params),
+            buffers: MaskBuffers::default(),
+            mask: Mat::default(),
+            keypoints: Vector::new(),
+            gray: Mat::default(),
+            weighted: Mat::default(),
+            selection: Selection::default(),
+            localisation: Localisation::default(),
+            magnet: None,
+            previous: None,
+            roi: RoiState::default(),
+            frame_size: Size::default(),
+            detection: Detection::default(),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn threshold(&self) -> Threshold {
+        self.threshold
+    }
+
+    /// Cheap, takes effect on the next frame
+    pub fn set_threshold(&mut self, threshold: impl Into<Threshold>) {
+        self.threshold = threshold.into();
+    }
+
+    pub fn params(&self) -> BlobParams {
+        self.params
+    }
+
+    /// Rebuilds the detector if `params` differ from the current ones
+    pub fn set_params(&mut self, params: BlobParams) {
+        if params != self.params {
+            self.params = params;
+            self.detector = create_blob_detector(&params);
+        }
+    }
+
+    /// Mask of the pixels that matched the threshold in the last tracked frame,
+    /// only covering `window` if it was set
+    pub fn mask(&self) -> &Mat {
+        &self.mask
+    }
+
+    /// Like `mask`, placed where it was in a frame-sized `dst` that's
+    /// black outside the window, e.g. for showing it in place of the frame
+    pub fn frame_mask(&self, dst: &mut Mat) {
+        let Some(window) = self.roi.window else {
+            self.mask.copy_to(dst).unwrap();
+            return;
+        };
+
+        if dst.size().unwrap() != self.frame_size {
+            *dst = Mat::new_size_with_default(self.frame_size, CV_8UC1, Scalar::all(0.)).unwrap();
+        } else {
+            dst.set_to(&Scalar::all(0.), &cv::core::no_array()).unwrap();
+        }
+        // a header pointing into `dst`, so copying into it fills the window
+        let mut roi = Mat::roi(dst, window).unwrap();
+        self.mask.copy_to(&mut roi).unwrap();
+    }
+
+    pub fn selection(&self) -> Selection {
+        self.selection
+    }
+
+    pub fn set_selection(&mut self, selection: Selection) {
+        self.selection = selection;
+    }
+
+    pub fn localisation(&self) -> Localisation {
+        self.localisation
+    }
+
+    pub fn set_localisation(&mut self, localisation: Localisation) {
+        self.localisation = localisation;
+    }
+
+    /// Region-of-interest settings, along with selection and localisation
+    pub fn set_config(&mut self, config: &TrackerConfig) {
+        self.selection = config.selection;
+        self.localisation = config.localisation;
+
+        self.roi.enabled = config.roi;
+        self.roi.margin = config.roi_margin;
+        self.roi.min_confidence = config.roi_min_confidence;
+        self.roi.max_misses = config.roi_max_misses;
+    }
+
+    /// Where the ball is expected in the next frame, e.g. from a `Kalman`.
+    /// Centers the search window, otherwise it stays on the last detection.
+    pub fn set_prediction(&mut self, prediction: Option<(f32, f32)>) {
+        self.roi.prediction = prediction;
+    }
+
+    /// Part of the frame searched last, `None` for all of it
+    pub fn window(&self) -> Option<Rect> {
+        self.roi.window
+    }
+
+    /// Used by `Selection::ClosestToMagnet`
+    pub fn set_magnet(&mut self, magnet: Option<(f32, f32)>) {
+        self.magnet = magnet;
+    }
+
+    pub fn track(&mut self, frame: &Mat) -> Option<Ball> {
+        self.detect(frame).ball()
+    }
+
+    pub fn track_at(&mut self, frame: &Mat, timestamp: Duration) -> Option<Ball> {
+        self.detect_at(frame, timestamp).ball()
+    }
+
+    /// Time since the tracker was created, which `track` and `detect`
+    /// use as the frame's timestamp
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Like `detect_at`, timestamped with `elapsed`
+    pub fn detect(&mut self, frame: &Mat) -> &Detection {
+        self.detect_at(frame, self.elapsed())
+    }
+
+    /// `timestamp` is when `frame` was captured, e.g. the position in a recording
+    pub fn detect_at(&mut self, frame: &Mat, timestamp: Duration) -> &Detection {
+        self.frame_size = frame.size().unwrap();
+        self.roi.window = self.roi.window(self.frame_size);
+        let roi;
+        let (frame, offset) = match self.roi.window {
+            Some(window) => {
+                // just a header pointing into the frame, no pixels are copied
+                roi = Mat::roi(frame, window).unwrap();
+                (&roi, window.tl())
+            }
+            None => (frame, Point::default()),
+        };
+
+        threshold_mask(frame, self.threshold, &mut self.buffers, &mut self.mask);
+
+        self.keypoints.clear();
+        self.detector
+            .detect(&self.mask, &mut self.keypoints, &cv::core::no_array())
+            .unwrap();
+
+        if self.localisation == Localisation::WeightedMoments {
+            cv::imgproc::cvt_color(frame, &mut self.gray, cv::imgproc::COLOR_BGR2GRAY, 0).unwrap();
+        }
+
+        self.detection.candidates.clear();
+        for kp in &self.keypoints {
+            let radius = kp.size / 2.;
+            let bounds = blob_bounds(&self.mask, kp.pt.x, kp.pt.y, radius);
+
+            let mut area = 0.;
+            let mut covered = 0.;
+            let mut center = (kp.pt.x, kp.pt.y);
+            if let Some(bounds) = bounds {
+                let mask = Mat::roi(&self.mask, bounds).unwrap();
+                area = cv::core::count_non_zero(&mask).unwrap() as f32;
+                covered = count_in_circle(&mask, bounds, center, radius);
+
+                let moments = match self.localisation {
+                    Localisation::Keypoint => None,
+                    Localisation::Moments => Some(cv::imgproc::moments(&mask, true).unwrap()),
+                    Localisation::WeightedMoments => {
+                        // the mask is 0 or 255, so min() zeroes everything outside it
+                        let gray = Mat::roi(&self.gray, bounds).unwrap();
+                        cv::core::min(&gray, &mask, &mut self.weighted).unwrap();
+                        Some(cv::imgproc::moments(&self.weighted, false).unwrap())
+                    }
+                };
+
+                if let Some(m) = moments.filter(|m| m.m00 > 0.) {
+                    center = (
+                        (m.m10 / m.m00) as f32 + bounds.x as f32,
+                        (m.m01 / m.m00) as f32 + bounds.y as f32,
+                    );
+                }
+            }
+
+            let confidence = if radius > 0. {
+                (covered / (PI * radius * radius)).min(1.)
+            } else {
+                0.
+            };
+
+            self.detection.candidates.push(Ball {
+                x: center.0 + offset.x as f32,
+                y: center.1 + offset.y as f32,
+                radius,
+                area,
+                confidence,
+                timestamp,
+            });
+        }
+
+        self.detection.chosen = self.choose();
+        // a position from before the ball was lost says nothing about where it is now
+        self.previous = self.detection.ball();
+        self.roi.record(self.detection.ball());
+
+        &self.detection
+    }
+
+    fn choose(&self) -> Option<usize> {
+        let candidates = self.detection.candidates.iter().enumerate();
+        let by = |f: fn(&Ball) -> f32| {
+            // total_cmp gives a deterministic order even for NaN,
+            // and on ties the first candidate wins
+            candidates
+                .clone()
+                .max_by(|(i, a), (j, b)| f(a).total_cmp(&f(b)).then(j.cmp(i)))
+                .map(|(i, _)| i)
+        };
+        let closest_to = |pos| {
+            candidates
+                .clone()
+                .min_by(|(i, a), (j, b)| {
+                    a.distance_sq(pos)
+                        .total_cmp(&b.distance_sq(pos))
+                        .then(i.cmp(j))
+                })
+                .map(|(i, _)| i)
+        };
+
+        match (self.selection, self.previous, self.magnet) {
+            (Selection::ClosestToPrevious, Some(prev), _) => closest_to((prev.x, prev.y)),
+            (Selection::ClosestToMagnet, _, Some(magnet)) => closest_to(magnet),
+            (Selection::HighestConfidence, _, _) => by(|c| c.confidence),
+            _ => by(|c| c.radius),
+        }
+    }
+}
+
+// bounding box of the circle at (x, y), clipped to the mask
+fn blob_bounds(mask: &Mat, x: f32, y: f32, radius: f32) -> Option<Rect> {
+    let size = mask.size().unwrap();
+    let bounds = Rect::new(
+        (x - radius).floor() as i32,
+        (y - radius).floor() as i32,
+        (2. * radius).ceil() as i32 + 1,
+        (2. * radius).ceil() as i32 + 1,
+    ) & Rect::new(0, 0, size.width, size.height);
+
+    (bounds.width > 0 && bounds.height > 0).then_some(bounds)
+}
+
+// matching pixels within `radius` of (x, y), where `mask` covers `bounds`
+fn count_in_circle(mask: &Mat, bounds: Rect, (x, y): (f32, f32), radius: f32) -> f32 {
+    let radius_sq = radius * radius;
+    let mut count = 0;
+    for row in 0..mask.rows() {
+        let dy = (bounds.y + row) as f32 - y;
+        let pixels = mask.at_row::<u8>(row).unwrap();
+        count += pixels
+            .iter()
+            .enumerate()
+            .filter(|&(col, &pixel)| {
+                let dx = (bounds.x + col as i32) as f32 - x;
+                pixel > 0 && dx * dx + dy * dy <= radius_sq
+            })
+            .count();
+    }
+    count as f32
+}
@@ -0,0 +1,350 @@
+//! Detection accuracy on synthetic scenes, where the ball's true
+//! position is known.
+
+use opencv::{core::Mat, prelude::*};
+
+use levitation::color::{Color, ColorRange, Hsv};
+use levitation::synth::{Disc, Scene};
+use levitation::tracker::{BlobParams, Detection, Selection, TrackerConfig};
+use levitation::{Ball, Localisation, Threshold, Tracker};
+
+fn ball_color() -> Color {
+    Scene::default().ball.unwrap().color
+}
+
+fn rgb_threshold() -> Threshold {
+    ColorRange::from_tolerance(ball_color(), Color::new(60, 60, 60)).into()
+}
+
+// wide on V, so the lighting gradient doesn't matter
+fn hsv_threshold() -> Threshold {
+    ColorRange::from_tolerance(ball_color().to_hsv(), Hsv::new(8, 70, 120)).into()
+}
+
+// what a real setup uses to ignore specks of noise
+fn params() -> BlobParams {
+    BlobParams {
+        filter_by_area: true,
+        min_area: 100.,
+        max_area: 1e5,
+        ..BlobParams::default()
+    }
+}
+
+fn render(scene: &Scene) -> Mat {
+    let mut frame = Mat::default();
+    scene.render(&mut frame);
+    frame
+}
+
+fn detect(scene: &Scene, threshold: Threshold, localisation: Localisation) -> Detection {
+    let mut tracker = Tracker::with_params(threshold, params());
+    tracker.set_localisation(localisation);
+    tracker.detect(&render(scene)).clone()
+}
+
+fn error(ball: &Ball, truth: &Disc) -> f32 {
+    (ball.x - truth.x).hypot(ball.y - truth.y)
+}
+
+// deterministic positions spread over the frame, with fractional parts
+fn positions(n: usize) -> impl Iterator<Item = (f32, f32)> {
+    let mut state = 12345u32;
+    let mut next = move || {
+        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        (state >> 8) as f32 / (1 << 24) as f32
+    };
+    (0..n).map(move |_| (60. + next() * 520., 60. + next() * 360.))
+}
+
+fn scene_at(x: f32, y: f32) -> Scene {
+    let mut scene = Scene::default();
+    scene.ball.as_mut().unwrap().x = x;
+    scene.ball.as_mut().unwrap().y = y;
+    scene
+}
+
+#[test]
+fn clean_scene() {
+    let scene = Scene::default();
+    let truth = scene.ball.unwrap();
+
+    for &localisation in Localisation::ALL {
+        for threshold in [rgb_threshold(), hsv_threshold()] {
+            let detection = detect(&scene, threshold, localisation);
+            assert_eq!(detection.candidates.len(), 1, "{localisation}");
+
+            let ball = detection.ball().unwrap();
+            assert!(error(&ball, &truth) < 0.5, "{localisation}: {ball:?}");
+            assert!(
+                (ball.radius - truth.radius).abs() < 1.5,
+                "{localisation}: {ball:?}"
+            );
+            assert!(ball.confidence > 0.8, "{localisation}: {ball:?}");
+        }
+    }
+}
+
+#[test]
+fn sub_pixel_accuracy() {
+    for &localisation in Localisation::ALL {
+        let tolerance = match localisation {
+            Localisation::Keypoint => 0.5,
+            _ => 0.25,
+        };
+
+        let mut total = 0.;
+        for (x, y) in positions(20) {
+            let scene = scene_at(x, y);
+            let ball = detect(&scene, rgb_threshold(), localisation)
+                .ball()
+                .unwrap();
+
+            let e = error(&ball, &scene.ball.unwrap());
+            assert!(e < tolerance, "{localisation} at ({x}, {y}): {ball:?}");
+            total += e;
+        }
+        assert!(total / 20. < tolerance / 2., "{localisation}");
+    }
+}
+
+#[test]
+fn noise_and_lighting() {
+    for (i, (x, y)) in positions(20).enumerate() {
+        let scene = Scene {
+            gradient: 0.5,
+            noise: 5.,
+            seed: i as u64,
+            ..scene_at(x, y)
+        };
+        let detection = detect(&scene, hsv_threshold(), Localisation::Moments);
+
+        let ball = detection.ball().expect("ball not found");
+        assert!(
+            error(&ball, &scene.ball.unwrap()) < 1.,
+            "({x}, {y}): {ball:?}"
+        );
+    }
+}
+
+#[test]
+fn motion_blur() {
+    for blur in [(8., 0.), (0., 12.), (6., -6.)] {
+        let scene = Scene {
+            blur,
+            ..Scene::default()
+        };
+        let ball = detect(&scene, rgb_threshold(), Localisation::Moments)
+            .ball()
+            .expect("ball not found");
+
+        // the smear is symmetric about the ball's mid-exposure position
+        assert!(
+            error(&ball, &scene.ball.unwrap()) < 1.,
+            "{blur:?}: {ball:?}"
+        );
+    }
+}
+
+#[test]
+fn other_colors_ignored() {
+    let scene = Scene {
+        distractors: vec![
+            Disc::new(100., 100., 30., Color::new(40, 40, 230)),
+            Disc::new(500., 380., 25., Color::new(40, 200, 40)),
+            Disc::new(520., 100., 15., Color::white()),
+        ],
+        ..Scene::default()
+    };
+
+    for threshold in [rgb_threshold(), hsv_threshold()] {
+        let detection = detect(&scene, threshold, Localisation::Moments);
+        assert_eq!(detection.candidates.len(), 1);
+        assert!(error(&detection.ball().unwrap(), &scene.ball.unwrap()) < 0.5);
+    }
+}
+
+#[test]
+fn largest_blob_chosen() {
+    let scene = Scene {
+        distractors: vec![
+            Disc::new(100., 100., 8., ball_color()),
+            Disc::new(540., 400., 12., ball_color()),
+        ],
+        ..Scene::default()
+    };
+
+    let detection = detect(&scene, rgb_threshold(), Localisation::Moments);
+    assert_eq!(detection.candidates.len(), 3);
+    assert!(error(&detection.ball().unwrap(), &scene.ball.unwrap()) < 0.5);
+}
+
+fn tracker(selection: Selection) -> Tracker {
+    let mut tracker = Tracker::with_params(rgb_threshold(), params());
+    tracker.set_localisation(Localisation::Moments);
+    tracker.set_selection(selection);
+    tracker
+}
+
+#[test]
+fn closest_to_previous_chosen() {
+    let mut tracker = tracker(Selection::ClosestToPrevious);
+    let alone = scene_at(450., 240.);
+    assert!(tracker.track(&render(&alone)).is_some());
+
+    let scene = Scene {
+        distractors: vec![Disc::new(150., 240., 30., ball_color())],
+        ..scene_at(455., 240.)
+    };
+    let ball = tracker.track(&render(&scene)).unwrap();
+    assert!(error(&ball, &scene.ball.unwrap()) < 0.5);
+
+    // once the ball is lost there's nothing to be close to
+    let empty = Scene {
+        ball: None,
+        ..Scene::default()
+    };
+    assert!(tracker.track(&render(&empty)).is_none());
+    let ball = tracker.track(&render(&scene)).unwrap();
+    assert!(error(&ball, &scene.distractors[0]) < 0.5);
+}
+
+#[test]
+fn closest_to_magnet_chosen() {
+    let scene = Scene {
+        distractors: vec![Disc::new(150., 240., 30., ball_color())],
+        ..scene_at(450., 240.)
+    };
+    let mut tracker = tracker(Selection::ClosestToMagnet);
+
+    // no magnet selected yet
+    let ball = tracker.track(&render(&scene)).unwrap();
+    assert!(error(&ball, &scene.distractors[0]) < 0.5);
+
+    tracker.set_magnet(Some((450., 100.)));
+    let ball = tracker.track(&render(&scene)).unwrap();
+    assert!(error(&ball, &scene.ball.unwrap()) < 0.5);
+}
+
+#[test]
+fn highest_confidence_chosen() {
+    // a bigger blob with holes in it, covering about 80% of its circle
+    let mut distractors = vec![Disc::new(150., 240., 40., ball_color())];
+    for (dx, dy) in [(-20., -20.), (20., -20.), (-20., 20.), (20., 20.)] {
+        distractors.push(Disc::new(150. + dx, 240. + dy, 9., Color::new(40, 40, 40)));
+    }
+    let scene = Scene {
+        distractors,
+        ..scene_at(450., 240.)
+    };
+
+    let detection = tracker(Selection::Largest).detect(&render(&scene)).clone();
+    assert_eq!(detection.candidates.len(), 2);
+    let holed = detection.ball().unwrap();
+    assert!(error(&holed, &scene.distractors[0]) < 1.);
+    assert!(holed.confidence < 0.9, "{holed:?}");
+
+    let ball = tracker(Selection::HighestConfidence)
+        .track(&render(&scene))
+        .unwrap();
+    assert!(error(&ball, &scene.ball.unwrap()) < 0.5);
+    assert!(ball.confidence > 0.9, "{ball:?}");
+}
+
+#[test]
+fn false_positive_rate() {
+    let frames = 50;
+    let mut false_positives = 0;
+
+    for seed in 0..frames {
+        let scene = Scene {
+            ball: None,
+            distractors: vec![
+                Disc::new(150., 120., 30., Color::new(40, 40, 230)),
+                Disc::new(450., 300., 20., Color::new(200, 200, 200)),
+            ],
+            gradient: 0.5,
+            noise: 5.,
+            seed,
+            ..Scene::default()
+        };
+
+        for threshold in [rgb_threshold(), hsv_threshold()] {
+            if detect(&scene, threshold, Localisation::Moments)
+                .ball()
+                .is_some()
+            {
+                false_positives += 1;
+            }
+        }
+    }
+
+    let rate = false_positives as f32 / (2 * frames) as f32;
+    assert_eq!(false_positives, 0, "false positive rate {rate}");
+}
+
+fn roi_tracker() -> Tracker {
+    let mut tracker = tracker(Selection::Largest);
+    tracker.set_config(&TrackerConfig {
+        roi: true,
+        localisation: Localisation::Moments,
+        ..TrackerConfig::default()
+    });
+    tracker
+}
+
+#[test]
+fn roi_window_clipped() {
+    let size = Scene::default().size;
+    for (x, y) in [(25., 25.), (615., 455.)] {
+        let mut tracker = roi_tracker();
+        let scene = scene_at(x, y);
+        tracker.track(&render(&scene)).unwrap();
+        assert_eq!(tracker.window(), None, "the first frame is searched whole");
+
+        let ball = tracker.track(&render(&scene)).unwrap();
+        assert!(error(&ball, &scene.ball.unwrap()) < 0.5);
+        let window = tracker.window().unwrap();
+        assert!(window.x >= 0 && window.y >= 0, "{window:?}");
+        assert!(window.x + window.width <= size.width, "{window:?}");
+        assert!(window.y + window.height <= size.height, "{window:?}");
+        // cut short on the side of the edge
+        assert!(window.width < 2 * (20 + 40), "{window:?}");
+    }
+}
+
+#[test]
+fn roi_candidates_in_frame_coordinates() {
+    let mut tracker = roi_tracker();
+    tracker.track(&render(&scene_at(400., 300.))).unwrap();
+
+    let scene = scene_at(408.5, 294.25);
+    let ball = tracker.track(&render(&scene)).unwrap();
+    let window = tracker.window().unwrap();
+    assert!(window.x > 0 && window.y > 0, "{window:?}");
+    assert!(error(&ball, &scene.ball.unwrap()) < 0.25, "{ball:?}");
+
+    // shown in place, the window's pixels land where the ball is
+    let mut mask = Mat::default();
+    tracker.frame_mask(&mut mask);
+    assert_eq!(mask.size().unwrap(), Scene::default().size);
+    assert_eq!(*mask.at_2d::<u8>(294, 408).unwrap(), 255);
+    assert_eq!(*mask.at_2d::<u8>(10, 10).unwrap(), 0);
+}
+
+#[test]
+fn roi_falls_back_to_whole_frame() {
+    let max_misses = TrackerConfig::default().roi_max_misses;
+    let mut tracker = roi_tracker();
+    tracker.track(&render(&scene_at(150., 240.))).unwrap();
+
+    // the ball reappears far away, outside the window
+    let moved = scene_at(500., 240.);
+    for _ in 0..=max_misses {
+        assert!(tracker.track(&render(&moved)).is_none());
+        assert!(tracker.window().is_some());
+    }
+    let ball = tracker.track(&render(&moved)).unwrap();
+    assert_eq!(tracker.window(), None);
+    assert!(error(&ball, &moved.ball.unwrap()) < 0.5);
+}
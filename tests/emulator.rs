@@ -0,0 +1,88 @@
+//! The serial link against the firmware emulator on a pseudo terminal.
+#![cfg(unix)]
+
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serialport::{SerialPort, TTYPort};
+
+use levitation::control::PidConfig;
+use levitation::emulator::Emulator;
+use levitation::plant::PlantConfig;
+use levitation::serial::{Link, Packet, PortId, SerialConfig, Telemetry};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// runs the emulator until the returned flag is set
+fn spawn_emulator() -> (String, Arc<AtomicBool>, thread::JoinHandle<()>) {
+    let (mut port, host) = TTYPort::pair().unwrap();
+    port.set_timeout(Duration::from_millis(1)).unwrap();
+    let name = host.name().unwrap();
+    drop(host);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let mut emulator = Emulator::new(PlantConfig::default(), PidConfig::default());
+            while !stop.load(SeqCst) {
+                if emulator.poll(&mut port).is_err() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+        })
+    };
+    (name, stop, thread)
+}
+
+fn wait_for(link: &Link, done: impl Fn(&Telemetry) -> bool) -> Telemetry {
+    let start = Instant::now();
+    loop {
+        if let Some(t) = link.telemetry().find(|t| done(t)) {
+            return t;
+        }
+        assert!(start.elapsed() < TIMEOUT, "{}", link.state());
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn commands_and_telemetry() {
+    let (name, stop, emulator) = spawn_emulator();
+    let link = Link::spawn(PortId::name(&name), &SerialConfig::default());
+
+    // the coil is off until told otherwise
+    let t = wait_for(&link, |_| true);
+    assert_eq!(t.duty, 0.);
+    assert!(t.faults.is_empty());
+
+    link.send(Packet::Command(0.8));
+    let t = wait_for(&link, |t| t.duty == 0.8 && t.current > 0.5);
+    assert!(t.current < 0.8 * 24. / 10.);
+
+    link.send(Packet::Enable(false));
+    wait_for(&link, |t| t.duty == 0.);
+
+    stop.store(true, SeqCst);
+    emulator.join().unwrap();
+}
+
+#[test]
+fn reconnects() {
+    let (name, stop, emulator) = spawn_emulator();
+
+    // the host going away and coming back, like an adapter being replugged.
+    // Going away turns the coil off.
+    for command in [0.3, 0.6] {
+        let link = Link::spawn(PortId::name(&name), &SerialConfig::default());
+        wait_for(&link, |_| true);
+        link.send(Packet::Enable(true));
+        link.send(Packet::Command(command));
+        wait_for(&link, |t| t.duty == command);
+    }
+
+    stop.store(true, SeqCst);
+    emulator.join().unwrap();
+}
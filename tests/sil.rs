@@ -0,0 +1,63 @@
+//! Closed loop from rendered frames to the plant, see `levitation::sil`.
+
+use std::time::Duration;
+
+use levitation::control::PidConfig;
+use levitation::plant::PlantConfig;
+use levitation::sil::{Harness, SilConfig};
+
+const SETTLED: Duration = Duration::from_secs(1);
+
+#[test]
+fn holds_ball() {
+    let mut harness = Harness::new(
+        SilConfig::default(),
+        PlantConfig::default(),
+        PidConfig::default(),
+    );
+    let report = harness.run();
+
+    assert!(!report.lost);
+    // one tracker for the whole run, searching around the ball by now
+    assert!(harness.tracker().window().is_some());
+    assert_eq!(report.detections(), report.steps.len());
+    let measurement_error = report.measurement_error().unwrap();
+    assert!(measurement_error < 0.1, "{measurement_error}");
+    let rms_error = report.rms_error(SETTLED).unwrap();
+    assert!(rms_error < 0.3, "{rms_error}");
+    assert!(
+        report.max_error(SETTLED) < 1.,
+        "{}",
+        report.max_error(SETTLED)
+    );
+}
+
+#[test]
+fn latency_hurts() {
+    let run = |latency| {
+        let config = SilConfig {
+            latency,
+            ..SilConfig::default()
+        };
+        Harness::new(config, PlantConfig::default(), PidConfig::default()).run()
+    };
+
+    let fast = run(0.005);
+    let slow = run(0.03);
+    assert!(!fast.lost);
+    assert!(slow.lost || slow.rms_error(SETTLED) > fast.rms_error(SETTLED));
+}
+
+#[test]
+fn empty_run() {
+    let config = SilConfig {
+        duration: 0.,
+        ..SilConfig::default()
+    };
+    let report = Harness::new(config, PlantConfig::default(), PidConfig::default()).run();
+
+    assert!(report.steps.is_empty());
+    assert!(!report.lost);
+    assert_eq!(report.measurement_error(), None);
+    assert_eq!(report.rms_error(SETTLED), None);
+}